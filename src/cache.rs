@@ -0,0 +1,377 @@
+//! # Cache
+//!
+//! Answers of upstream resolvers, kept for as long as their TTLs allow
+
+use crate::config::CacheConfig;
+use crate::message::{
+    Message, Name, Qclass, Qtype, Question, RData, ResourceRecord, ResponseCode, Type,
+};
+use log::trace;
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Answers to questions, bounded in number, with the least recently used ones evicted first
+///
+/// Both positive answers and negative ones, NXDOMAIN and NODATA, are kept.
+#[derive(Debug)]
+pub struct Cache {
+    /// The answers, by question
+    entries: Mutex<LruCache<Key, Entry>>,
+
+    /// The least time to keep an answer for, in seconds, even if its TTL is lower
+    min_ttl: u32,
+
+    /// The most time to keep an answer for, in seconds, even if its TTL is higher
+    max_ttl: u32,
+}
+
+/// What an answer is kept by: the question, and whether DNSSEC records were asked for
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct Key {
+    qname: Name,
+    qtype: Qtype,
+    qclass: Qclass,
+    dnssec_ok: bool,
+}
+
+/// An answer, with the TTLs of its records as they were when it was stored
+#[derive(Clone, Debug)]
+struct Entry {
+    /// The answer
+    answer: Message,
+
+    /// When the answer was stored
+    stored: Instant,
+
+    /// How long the answer is kept for
+    ttl: Duration,
+}
+
+impl Cache {
+    /// A cache according to `config`, unless it's disabled by having no room for any entries
+    pub fn new(config: &CacheConfig) -> Option<Self> {
+        Some(Self {
+            entries: Mutex::new(LruCache::new(NonZeroUsize::new(config.max_entries)?)),
+            min_ttl: config.min_ttl,
+            max_ttl: config.max_ttl,
+        })
+    }
+
+    /// The answer to `question`, if there is one that hasn't expired
+    ///
+    /// The TTLs of the records are decreased by the time that the answer has spent in the cache.
+    /// The answer isn't authoritative, as it's come from the cache.
+    pub fn get(&self, question: &Question, dnssec_ok: bool) -> Option<Message> {
+        let key = Key::new(question, dnssec_ok);
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let entry = entries.get(&key)?;
+
+        let elapsed = entry.stored.elapsed();
+        if elapsed >= entry.ttl {
+            trace!("The cached answer for {} has expired", question.qname);
+            entries.pop(&key);
+            return None;
+        }
+
+        let elapsed = elapsed.as_secs() as u32;
+        let mut answer = entry.answer.clone();
+        answer.header.aa = 0;
+        for rr in records_mut(&mut answer) {
+            rr.ttl = rr.ttl.saturating_sub(elapsed);
+        }
+        Some(answer)
+    }
+
+    /// Keep `answer` to `question`, if it can be cached
+    ///
+    /// A positive answer is kept for the lowest TTL of its records.
+    /// A negative answer, NXDOMAIN or NODATA, is kept for the lower of the TTL of the SOA record
+    /// in its authority section and the SOA's minimum field, and isn't kept without a SOA record.
+    /// The TTLs are clamped to the limits of the cache. Truncated answers and errors aren't kept.
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc2308#section-5
+    pub fn insert(&self, question: &Question, dnssec_ok: bool, answer: &Message) {
+        if answer.header.tc == 1 {
+            return;
+        }
+
+        let mut answer = answer.clone();
+        let negative = match answer.header.rcode {
+            ResponseCode::NoError => answer.answer.is_empty(),
+            ResponseCode::NameError => true,
+            _ => return,
+        };
+        if negative {
+            let Some((soa, minimum)) = answer.authority.iter_mut().find_map(|rr| match rr.rdata {
+                RData::SOA { minimum, .. } => Some((rr, minimum)),
+                _ => None,
+            }) else {
+                return;
+            };
+            soa.ttl = soa.ttl.min(minimum);
+        }
+
+        let mut ttl = u32::MAX;
+        for rr in records_mut(&mut answer) {
+            rr.ttl = rr.ttl.clamp(self.min_ttl, self.max_ttl);
+            ttl = ttl.min(rr.ttl);
+        }
+        if negative {
+            // Only the SOA record decides how long a negative answer is kept.
+            ttl = answer
+                .authority
+                .iter()
+                .find(|rr| matches!(rr.rdata, RData::SOA { .. }))
+                .map_or(0, |rr| rr.ttl);
+        }
+        if ttl == 0 {
+            return;
+        }
+
+        trace!("Caching the answer for {} for {} s", question.qname, ttl);
+        let entry = Entry {
+            answer,
+            stored: Instant::now(),
+            ttl: Duration::from_secs(ttl.into()),
+        };
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .put(Key::new(question, dnssec_ok), entry);
+    }
+
+    /// Take over the answers of `old`, the cache that this one replaces, as far as there's room
+    ///
+    /// The most recently used answers are the ones that are kept, and their order of use stays.
+    pub fn inherit(&self, old: &Cache) {
+        let old = old.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        for (key, entry) in old.iter().rev() {
+            entries.put(key.clone(), entry.clone());
+        }
+    }
+}
+
+impl Key {
+    fn new(question: &Question, dnssec_ok: bool) -> Self {
+        Self {
+            qname: question.qname.clone(),
+            qtype: question.qtype,
+            qclass: question.qclass,
+            dnssec_ok,
+        }
+    }
+}
+
+/// All the records of `msg` that have a TTL
+///
+/// The OPT record has flags in place of a TTL, so it's left out.
+fn records_mut(msg: &mut Message) -> impl Iterator<Item = &mut ResourceRecord> {
+    msg.answer
+        .iter_mut()
+        .chain(msg.authority.iter_mut())
+        .chain(msg.additional.iter_mut())
+        .filter(|rr| rr.type_ != Type::OPT)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::Cache;
+    use crate::config::CacheConfig;
+    use crate::message::{
+        Class, Edns, Header, Message, OpCode, Qclass, Qr, Qtype, Question, RData,
+    };
+    use crate::message::{ResourceRecord, ResponseCode, Type};
+    use std::net::Ipv4Addr;
+    use std::time::{Duration, Instant};
+
+    fn question(name: &str) -> Question {
+        Question::new(name.parse().unwrap(), Qtype::A, Qclass::IN)
+    }
+
+    fn answer(
+        rcode: ResponseCode,
+        answer: Vec<ResourceRecord>,
+        authority: Vec<ResourceRecord>,
+    ) -> Message {
+        Message {
+            header: Header {
+                id: 1,
+                qr: Qr::Response,
+                opcode: OpCode::Query,
+                aa: 1,
+                tc: 0,
+                rd: 1,
+                ra: 1,
+                z: 0,
+                rcode,
+                qdcount: 1,
+                ancount: answer.len() as u16,
+                nscount: authority.len() as u16,
+                arcount: 0,
+            },
+            question: vec![question("example.com")],
+            answer,
+            authority,
+            additional: vec![],
+        }
+    }
+
+    fn a(ttl: u32) -> ResourceRecord {
+        ResourceRecord::new(
+            "example.com".parse().unwrap(),
+            Type::A,
+            Class::IN,
+            ttl,
+            RData::A(Ipv4Addr::new(1, 2, 3, 4)),
+        )
+    }
+
+    fn soa(ttl: u32, minimum: u32) -> ResourceRecord {
+        ResourceRecord::new(
+            "com".parse().unwrap(),
+            Type::SOA,
+            Class::IN,
+            ttl,
+            RData::SOA {
+                mname: "ns.com".parse().unwrap(),
+                rname: "admin.com".parse().unwrap(),
+                serial: 1,
+                refresh: 3600,
+                retry: 600,
+                expire: 86400,
+                minimum,
+            },
+        )
+    }
+
+    fn config(max_entries: usize) -> CacheConfig {
+        CacheConfig {
+            max_entries,
+            min_ttl: 5,
+            max_ttl: 600,
+        }
+    }
+
+    #[test]
+    fn positive_answers() {
+        let cache = Cache::new(&config(10)).unwrap();
+        let q = question("example.com");
+        assert!(cache.get(&q, false).is_none());
+
+        cache.insert(
+            &q,
+            false,
+            &answer(ResponseCode::NoError, vec![a(3600), a(1)], vec![]),
+        );
+        let cached = cache.get(&q, false).unwrap();
+        assert_eq!(0, cached.header.aa);
+        // Clamped to the limits
+        assert_eq!(
+            vec![600, 5],
+            cached.answer.iter().map(|rr| rr.ttl).collect::<Vec<_>>()
+        );
+        // Kept apart from answers with DNSSEC records
+        assert!(cache.get(&q, true).is_none());
+        assert!(cache.get(&question("EXAMPLE.com"), false).is_some());
+
+        // The TTLs decay, and the answer expires with the lowest of them.
+        let mut entries = cache.entries.lock().unwrap();
+        let entry = entries.iter_mut().next().unwrap().1;
+        entry.stored = Instant::now() - Duration::from_secs(3);
+        drop(entries);
+        let cached = cache.get(&q, false).unwrap();
+        assert_eq!(
+            vec![597, 2],
+            cached.answer.iter().map(|rr| rr.ttl).collect::<Vec<_>>()
+        );
+        let mut entries = cache.entries.lock().unwrap();
+        entries.iter_mut().next().unwrap().1.stored = Instant::now() - Duration::from_secs(5);
+        drop(entries);
+        assert!(cache.get(&q, false).is_none());
+
+        // The OPT record's flags aren't a TTL.
+        let q = question("edns.example.com");
+        let mut edns = answer(ResponseCode::NoError, vec![a(60)], vec![]);
+        edns.additional.push(Edns::new(1232).to_record());
+        cache.insert(&q, false, &edns);
+        let cached = cache.get(&q, false).unwrap();
+        assert_eq!(edns.additional, cached.additional);
+
+        // Errors aren't kept.
+        let q = question("servfail.example.com");
+        cache.insert(
+            &q,
+            false,
+            &answer(ResponseCode::ServerFailure, vec![], vec![]),
+        );
+        assert!(cache.get(&q, false).is_none());
+    }
+
+    #[test]
+    fn negative_answers() {
+        let cache = Cache::new(&config(10)).unwrap();
+
+        let q = question("nx.example.com");
+        cache.insert(
+            &q,
+            false,
+            &answer(ResponseCode::NameError, vec![], vec![soa(300, 60)]),
+        );
+        let cached = cache.get(&q, false).unwrap();
+        assert_eq!(ResponseCode::NameError, cached.header.rcode);
+        assert_eq!(60, cached.authority[0].ttl);
+
+        // NODATA
+        let q = question("nodata.example.com");
+        cache.insert(
+            &q,
+            false,
+            &answer(ResponseCode::NoError, vec![], vec![soa(30, 60)]),
+        );
+        assert_eq!(30, cache.get(&q, false).unwrap().authority[0].ttl);
+
+        // Without a SOA record, a negative answer isn't kept.
+        let q = question("nosoa.example.com");
+        cache.insert(&q, false, &answer(ResponseCode::NameError, vec![], vec![]));
+        assert!(cache.get(&q, false).is_none());
+    }
+
+    #[test]
+    fn least_recently_used_are_evicted() {
+        let cache = Cache::new(&config(2)).unwrap();
+        let (q1, q2, q3) = (question("a.com"), question("b.com"), question("c.com"));
+        let ok = answer(ResponseCode::NoError, vec![a(60)], vec![]);
+
+        cache.insert(&q1, false, &ok);
+        cache.insert(&q2, false, &ok);
+        assert!(cache.get(&q1, false).is_some());
+        cache.insert(&q3, false, &ok);
+
+        assert!(cache.get(&q1, false).is_some());
+        assert!(cache.get(&q2, false).is_none());
+        assert!(cache.get(&q3, false).is_some());
+
+        assert!(Cache::new(&config(0)).is_none());
+    }
+
+    #[test]
+    fn answers_are_inherited() {
+        let old = Cache::new(&config(3)).unwrap();
+        let (q1, q2, q3) = (question("a.com"), question("b.com"), question("c.com"));
+        let ok = answer(ResponseCode::NoError, vec![a(60)], vec![]);
+        for q in [&q1, &q2, &q3] {
+            old.insert(q, false, &ok);
+        }
+        assert!(old.get(&q1, false).is_some());
+
+        // The new cache only has room for the two most recently used answers.
+        let cache = Cache::new(&config(2)).unwrap();
+        cache.inherit(&old);
+        assert!(cache.get(&q1, false).is_some());
+        assert!(cache.get(&q2, false).is_none());
+        assert!(cache.get(&q3, false).is_some());
+    }
+}
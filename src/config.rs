@@ -0,0 +1,618 @@
+//! # Configuration
+//!
+//! Settings of the server, which come from a configuration file and from the command line
+
+use crate::constants::{
+    ARBITRARY_IPV4, CACHE_MAX_ENTRIES, CACHE_MAX_TTL, CACHE_MIN_TTL, DEFAULT_MAX_UDP_PAYLOAD,
+    LOCAL_SOCKET_ADDR_STR, NAME_SERVER_PORT, NAME_SERVER_TIMEOUT_MS, RESOLUTION_DEADLINE_MS, TTL,
+    UPSTREAM_ATTEMPTS, UPSTREAM_MAX_FAILURES, UPSTREAM_PROBE_INTERVAL_MS, UPSTREAM_RACE_COUNT,
+    UPSTREAM_TIMEOUT_MS,
+};
+use crate::errors::ConfigError;
+use crate::message::{Edns, Name};
+use log::LevelFilter;
+use serde::{de, Deserialize, Deserializer};
+use std::fmt;
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// How queries are answered
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// We resolve queries ourselves, recursively if there are root hints.
+    Resolve,
+
+    /// We forward queries to upstream resolvers.
+    Forward,
+}
+
+impl FromStr for Mode {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, ConfigError> {
+        match s {
+            "resolve" => Ok(Self::Resolve),
+            "forward" => Ok(Self::Forward),
+            _ => Err(ConfigError::InvalidMode(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Resolve => write!(f, "resolve"),
+            Self::Forward => write!(f, "forward"),
+        }
+    }
+}
+
+/// How the upstream resolvers are picked for a query; see [`crate::selection`]
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    /// In order of preference
+    #[default]
+    Order,
+
+    /// Starting from a different one every time
+    RoundRobin,
+
+    /// At random, in proportion to their weights
+    Random,
+
+    /// Starting from the one with the lowest smoothed round-trip time
+    Fastest,
+
+    /// Querying the fastest few at the same time
+    Race,
+}
+
+/// Settings of the server
+///
+/// Every setting is optional, and those that are missing get their defaults.
+///
+/// The configuration file is in TOML, with a table for each group of settings:
+///
+/// ```toml
+/// [server]
+/// listen = ["127.0.0.1:2053", "[::1]:2053"]
+/// mode = "forward"
+/// max_udp_payload = 1232
+///
+/// [upstreams]
+/// resolvers = ["8.8.8.8:53", "[2001:4860:4860::8888]:53"]
+/// timeout_ms = 2000
+/// attempts = 2
+/// max_failures = 3
+/// probe_interval_ms = 30000
+/// strategy = "order"
+///
+/// [[forward]]
+/// domain = "corp.example."
+/// resolvers = ["10.0.0.53:53"]
+///
+/// [[zone]]
+/// file = "example.com.zone"
+/// origin = "example.com."
+///
+/// [resolve]
+/// root_hints = "root.hints"
+/// port = 53
+/// timeout_ms = 1500
+/// deadline_ms = 10000
+///
+/// [cache]
+/// max_entries = 10000
+/// min_ttl = 0
+/// max_ttl = 86400
+///
+/// [log]
+/// level = "info"
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// How the server listens and answers
+    pub server: ServerConfig,
+
+    /// Upstream resolvers, for the forwarding mode
+    pub upstreams: UpstreamsConfig,
+
+    /// Conditional forwarding rules, in `[[forward]]` tables
+    pub forward: Vec<ForwardRule>,
+
+    /// Zones that we are authoritative for, in `[[zone]]` tables
+    pub zone: Vec<ZoneConfig>,
+
+    /// Answers in the resolver mode
+    pub resolve: ResolveConfig,
+
+    /// The cache of forwarded answers
+    pub cache: CacheConfig,
+
+    /// Logging
+    pub log: LogConfig,
+}
+
+/// The `[server]` table
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Addresses to listen on, over both UDP and TCP
+    pub listen: Vec<SocketAddr>,
+
+    /// How queries are answered; see [`Config::mode`] for the default
+    pub mode: Option<Mode>,
+
+    /// The largest UDP payload we send or accept with EDNS(0), in bytes
+    pub max_udp_payload: u16,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: vec![LOCAL_SOCKET_ADDR_STR.parse().expect("A valid address")],
+            mode: None,
+            max_udp_payload: DEFAULT_MAX_UDP_PAYLOAD,
+        }
+    }
+}
+
+/// The `[upstreams]` table
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamsConfig {
+    /// Upstream resolvers to forward queries to, in the forwarding mode, in order of preference
+    pub resolvers: Vec<SocketAddr>,
+
+    /// Time to wait for an answer to a single attempt, in milliseconds
+    pub timeout_ms: u64,
+
+    /// Number of attempts at every resolver, before failing over to the next one
+    pub attempts: u32,
+
+    /// Number of consecutive failed queries after which a resolver is marked down
+    pub max_failures: u32,
+
+    /// Time after which a resolver that is down is probed again, in milliseconds
+    pub probe_interval_ms: u64,
+
+    /// How the resolvers are picked for a query
+    pub strategy: Strategy,
+
+    /// Weights of the resolvers, in the same order, for [`Strategy::Random`]; 1 each by default
+    pub weights: Vec<u32>,
+
+    /// Number of resolvers that are queried at the same time, for [`Strategy::Race`]
+    pub race_count: usize,
+}
+
+impl Default for UpstreamsConfig {
+    fn default() -> Self {
+        Self {
+            resolvers: vec![],
+            timeout_ms: UPSTREAM_TIMEOUT_MS,
+            attempts: UPSTREAM_ATTEMPTS,
+            max_failures: UPSTREAM_MAX_FAILURES,
+            probe_interval_ms: UPSTREAM_PROBE_INTERVAL_MS,
+            strategy: Strategy::default(),
+            weights: vec![],
+            race_count: UPSTREAM_RACE_COUNT,
+        }
+    }
+}
+
+/// A `[[forward]]` table: a conditional forwarding rule
+///
+/// Questions about names in `domain` are forwarded to `resolvers`, in any mode.
+/// Where rules overlap, the one with the longest domain wins.
+/// The rest of the settings are those of the `[upstreams]` table.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ForwardRule {
+    /// The domain, such as `corp.example.`
+    #[serde(deserialize_with = "parse")]
+    pub domain: Name,
+
+    /// Upstream resolvers to forward questions in the domain to
+    pub resolvers: Vec<SocketAddr>,
+}
+
+/// A `[[zone]]` table: a zone that we are authoritative for, in any mode
+///
+/// Questions about names in the zone are answered from its records, unless a conditional forwarding
+/// rule for a domain below its origin covers them.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ZoneConfig {
+    /// The master file of the zone; relative to the configuration file, if it's in one
+    pub file: PathBuf,
+
+    /// The origin of the zone, such as `example.com.`; the owner of its SOA record by default
+    #[serde(default, deserialize_with = "parse_some")]
+    pub origin: Option<Name>,
+}
+
+/// The `[resolve]` table
+///
+/// With root hints, queries are resolved recursively, starting from the root servers;
+/// without them, every `A` query is answered with a fixed address.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResolveConfig {
+    /// A master file with the NS records of the root zone and the addresses of the root servers;
+    /// relative to the configuration file, if it's in one
+    pub root_hints: Option<PathBuf>,
+
+    /// The port that name servers are queried on
+    pub port: u16,
+
+    /// Time to wait for an answer from a name server, in milliseconds
+    pub timeout_ms: u64,
+
+    /// Time that resolving a single question may take, however many name servers it asks,
+    /// and however many aliases in its answer are followed, in milliseconds
+    pub deadline_ms: u64,
+
+    /// The address that every `A` query is answered with, without root hints
+    pub address: Ipv4Addr,
+
+    /// Time-to-live of the answers, in seconds, without root hints
+    pub ttl: u32,
+}
+
+impl Default for ResolveConfig {
+    fn default() -> Self {
+        Self {
+            root_hints: None,
+            port: NAME_SERVER_PORT,
+            timeout_ms: NAME_SERVER_TIMEOUT_MS,
+            deadline_ms: RESOLUTION_DEADLINE_MS,
+            address: ARBITRARY_IPV4.into(),
+            ttl: TTL,
+        }
+    }
+}
+
+/// The `[cache]` table
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Maximum number of answers in the cache; 0 disables it
+    pub max_entries: usize,
+
+    /// The least time to keep an answer for, in seconds, even if its TTL is lower
+    pub min_ttl: u32,
+
+    /// The most time to keep an answer for, in seconds, even if its TTL is higher
+    pub max_ttl: u32,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: CACHE_MAX_ENTRIES,
+            min_ttl: CACHE_MIN_TTL,
+            max_ttl: CACHE_MAX_TTL,
+        }
+    }
+}
+
+/// The `[log]` table
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// The most detailed level of messages to log, such as `info`;
+    /// the `RUST_LOG` environment variable decides if it's missing
+    #[serde(deserialize_with = "log_level")]
+    pub level: Option<LevelFilter>,
+}
+
+/// Deserialize a value from its presentation format, such as a domain name
+fn parse<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(de::Error::custom)
+}
+
+/// Deserialize a value that is optional from its presentation format
+fn parse_some<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    parse(deserializer).map(Some)
+}
+
+/// Deserialize a log level from its name, such as `info`
+fn log_level<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<LevelFilter>, D::Error> {
+    let level = String::deserialize(deserializer)?;
+    level
+        .parse()
+        .map(Some)
+        .map_err(|_| de::Error::custom(ConfigError::InvalidLogLevel(level)))
+}
+
+impl Config {
+    /// Read the configuration file at `path`
+    ///
+    /// Errors in the syntax and in the types of the values point to where they are in the file.
+    /// Relative paths of zone files, and of the root hints, are taken as relative to the directory
+    /// of the file.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| ConfigError::Read(path.display().to_string(), e))?;
+        let mut config: Self = toml::from_str(&contents)
+            .map_err(|e| ConfigError::Parse(path.display().to_string(), e))?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for zone in &mut config.zone {
+            zone.file = dir.join(&zone.file);
+        }
+        if let Some(root_hints) = &mut config.resolve.root_hints {
+            *root_hints = dir.join(&*root_hints);
+        }
+        Ok(config)
+    }
+
+    /// Check that the settings make sense together
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.mode()? == Mode::Forward && self.resolve.root_hints.is_some() {
+            return Err(ConfigError::Invalid(
+                "resolve.root_hints",
+                "they are only used in the resolver mode".to_string(),
+            ));
+        }
+
+        let listen = &self.server.listen;
+        if listen.is_empty() {
+            return Err(ConfigError::Invalid(
+                "server.listen",
+                "at least one address is needed".to_string(),
+            ));
+        }
+        if let Some(address) = duplicate(listen) {
+            return Err(ConfigError::Invalid(
+                "server.listen",
+                format!("{address} is given more than once"),
+            ));
+        }
+
+        if self.server.max_udp_payload < Edns::MIN_UDP_PAYLOAD_SIZE {
+            return Err(ConfigError::Invalid(
+                "server.max_udp_payload",
+                format!(
+                    "{} is less than {}, the size that every client accepts",
+                    self.server.max_udp_payload,
+                    Edns::MIN_UDP_PAYLOAD_SIZE
+                ),
+            ));
+        }
+
+        let resolvers = &self.upstreams.resolvers;
+        if let Some(resolver) = resolvers.iter().find(|resolver| resolver.port() == 0) {
+            return Err(ConfigError::Invalid(
+                "upstreams.resolvers",
+                format!("{resolver} has no port"),
+            ));
+        }
+        if let Some(resolver) = duplicate(resolvers) {
+            return Err(ConfigError::Invalid(
+                "upstreams.resolvers",
+                format!("{resolver} is given more than once"),
+            ));
+        }
+
+        for (key, value) in [
+            ("upstreams.timeout_ms", self.upstreams.timeout_ms),
+            ("upstreams.attempts", self.upstreams.attempts.into()),
+            ("upstreams.max_failures", self.upstreams.max_failures.into()),
+            ("upstreams.race_count", self.upstreams.race_count as u64),
+            ("resolve.port", self.resolve.port.into()),
+            ("resolve.timeout_ms", self.resolve.timeout_ms),
+            ("resolve.deadline_ms", self.resolve.deadline_ms),
+        ] {
+            if value == 0 {
+                return Err(ConfigError::Invalid(
+                    key,
+                    "it must be at least 1".to_string(),
+                ));
+            }
+        }
+
+        for (i, rule) in self.forward.iter().enumerate() {
+            if rule.resolvers.is_empty() {
+                return Err(ConfigError::Invalid(
+                    "forward",
+                    format!("the rule for {} has no resolvers", rule.domain),
+                ));
+            }
+            if let Some(resolver) = rule.resolvers.iter().find(|r| r.port() == 0) {
+                return Err(ConfigError::Invalid(
+                    "forward",
+                    format!("{resolver} in the rule for {} has no port", rule.domain),
+                ));
+            }
+            if self.forward[..i].iter().any(|r| r.domain == rule.domain) {
+                return Err(ConfigError::Invalid(
+                    "forward",
+                    format!("there is more than one rule for {}", rule.domain),
+                ));
+            }
+        }
+
+        let weights = &self.upstreams.weights;
+        if !weights.is_empty() && weights.len() != resolvers.len() {
+            return Err(ConfigError::Invalid(
+                "upstreams.weights",
+                format!(
+                    "there are {} weights for {} resolvers",
+                    weights.len(),
+                    resolvers.len()
+                ),
+            ));
+        }
+
+        // RFC 2181, section 8: a TTL is 31 bits long.
+        if self.resolve.ttl > i32::MAX as u32 {
+            return Err(ConfigError::Invalid(
+                "resolve.ttl",
+                format!("{} is more than {}", self.resolve.ttl, i32::MAX),
+            ));
+        }
+
+        if self.cache.min_ttl > self.cache.max_ttl {
+            return Err(ConfigError::Invalid(
+                "cache.min_ttl",
+                format!(
+                    "{} is more than `cache.max_ttl`, {}",
+                    self.cache.min_ttl, self.cache.max_ttl
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// The mode; it is the forwarding mode by default if there are any resolvers
+    ///
+    /// The forwarding mode needs at least one resolver, and resolvers are only used in that mode.
+    pub fn mode(&self) -> Result<Mode, ConfigError> {
+        match (self.server.mode, self.upstreams.resolvers.is_empty()) {
+            (None, true) => Ok(Mode::Resolve),
+            (None, false) => Ok(Mode::Forward),
+            (Some(Mode::Forward), true) => Err(ConfigError::NoResolvers),
+            (Some(Mode::Resolve), false) => Err(ConfigError::UnusedResolvers),
+            (Some(mode), _) => Ok(mode),
+        }
+    }
+}
+
+/// The first element of `items` that appears again later in it
+fn duplicate<T: PartialEq>(items: &[T]) -> Option<&T> {
+    items
+        .iter()
+        .enumerate()
+        .find(|(i, item)| items[i + 1..].contains(item))
+        .map(|(_, item)| item)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Config, Mode};
+    use crate::errors::ConfigError;
+    use log::LevelFilter;
+
+    #[test]
+    fn parse_and_validate() {
+        let config: Config = toml::from_str(
+            r#"
+            [server]
+            listen = ["127.0.0.1:53", "[::1]:53"]
+
+            [upstreams]
+            resolvers = ["8.8.8.8:53", "[2001:4860:4860::8888]:53"]
+
+            [[forward]]
+            domain = "corp.example"
+            resolvers = ["10.0.0.53:53"]
+
+            [[zone]]
+            file = "example.com.zone"
+
+            [[zone]]
+            file = "/var/lib/dns/0.10.in-addr.arpa.zone"
+            origin = "0.10.in-addr.arpa"
+
+            [log]
+            level = "info"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(2, config.server.listen.len());
+        assert_eq!(1232, config.server.max_udp_payload);
+        assert_eq!(60, config.resolve.ttl);
+        assert_eq!(Some(LevelFilter::Info), config.log.level);
+        assert_eq!("corp.example.", config.forward[0].domain.to_string());
+        assert_eq!(None, config.zone[0].origin);
+        assert_eq!(
+            "0.10.in-addr.arpa.",
+            config.zone[1].origin.as_ref().unwrap().to_string()
+        );
+        assert_eq!(Mode::Forward, config.mode().unwrap());
+        assert!(config.validate().is_ok());
+
+        let mut config = config;
+        config.server.mode = Some(Mode::Resolve);
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::UnusedResolvers)
+        ));
+
+        let mut config = Config::default();
+        config.server.mode = Some(Mode::Forward);
+        assert!(matches!(config.validate(), Err(ConfigError::NoResolvers)));
+
+        let mut config = Config::default();
+        config.server.max_udp_payload = 100;
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::Invalid("server.max_udp_payload", _))
+        ));
+
+        let mut config = Config::default();
+        config.upstreams.resolvers = vec!["1.1.1.1:53".parse().unwrap()];
+        config.upstreams.attempts = 0;
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::Invalid("upstreams.attempts", _))
+        ));
+
+        let mut config = Config::default();
+        config.upstreams.resolvers = vec!["1.1.1.1:53".parse().unwrap()];
+        config.resolve.root_hints = Some("root.hints".into());
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::Invalid("resolve.root_hints", _))
+        ));
+
+        let mut config = Config::default();
+        config.resolve.port = 0;
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::Invalid("resolve.port", _))
+        ));
+
+        let mut config = Config::default();
+        config.upstreams.resolvers = vec!["1.1.1.1:53".parse().unwrap(); 2];
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::Invalid("upstreams.resolvers", _))
+        ));
+    }
+
+    #[test]
+    fn parse_errors_point_to_the_key() {
+        let e = toml::from_str::<Config>("[server]\nport = 53").unwrap_err();
+        assert!(e.to_string().contains("unknown field `port`"));
+
+        let e = toml::from_str::<Config>("[log]\nlevel = \"loud\"").unwrap_err();
+        assert!(e.to_string().contains("line 2"));
+        assert!(e.to_string().contains("Invalid log level \"loud\""));
+
+        let e = toml::from_str::<Config>("[[forward]]\ndomain = \"a..b\"").unwrap_err();
+        assert!(e.to_string().contains("Empty label"));
+
+        let e = toml::from_str::<Config>("[upstreams]\nresolvers = [\"8.8.8.8\"]").unwrap_err();
+        assert!(e.to_string().contains("line 2"));
+    }
+}
@@ -1,53 +1,710 @@
 //! Connection and request handlers
 
-use crate::constants::{ARBITRARY_IPV4, BUFFER_LEN, TTL};
-use crate::errors::ConnectionError;
+use crate::cache::Cache;
+use crate::config::{Config, Mode, UpstreamsConfig};
+use crate::constants::{
+    BUFFER_LEN, MAX_CNAME_CHAIN, MAX_TCP_PIPELINED_QUERIES, TCP_IDLE_TIMEOUT_MS,
+};
+use crate::errors::{ConfigError, ConnectionError};
 use crate::message::{
-    Class, Header, Message, OpCode, Qr, Question, ResourceRecord, ResponseCode, Type,
+    Class, Edns, Header, Message, Name, OpCode, Qclass, Qr, Qtype, Question, RData, ResourceRecord,
+    ResponseCode, Type,
 };
+use crate::recursor::Recursor;
+use crate::upstream::Upstreams;
+use crate::zone::{Lookup, Zone};
 use anyhow::Result;
-use bytes::BytesMut;
-use deku::{DekuContainerRead, DekuContainerWrite};
-use log::{debug, info, trace};
-use std::iter::zip;
-use std::net::SocketAddrV4;
-use tokio::net::UdpSocket;
-
-pub async fn handle_request(
-    udp_socket: &UdpSocket,
-    resolver: Option<SocketAddrV4>,
-) -> Result<(), ConnectionError> {
-    //
-    // <== Query
-    //
-
-    let mut buf = [0u8; BUFFER_LEN];
-    let (received, source) = udp_socket
-        .recv_from(&mut buf)
-        .await
-        .map_err(ConnectionError::RecvError)?;
-    info!("<= Received {} bytes from {}", received, source);
-    // Remove mutability.
-    let buf = buf;
-
-    let (rest, qheader) = Header::from_bytes((&buf, 0))?;
-    let rest = rest.0;
-
-    let mut questions = vec![];
-    parse_question(&buf, rest, &qheader, &mut questions)?;
-
-    //
-    // --> Response
-    //
-
-    // Response code
-    let rcode = if qheader.opcode == OpCode::Query {
-        ResponseCode::NoError
-    } else {
-        ResponseCode::NotImplemented
+use deku::no_std_io::Cursor;
+use deku::prelude::*;
+use log::{debug, info, warn};
+use socket2::{Domain, Socket, Type as SocketType};
+use std::cmp::Reverse;
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{watch, Mutex, Semaphore};
+use tokio::time::{sleep, timeout};
+
+/// Bind a UDP socket to `address`, to receive queries on
+///
+/// See [`bind_tcp`] for `only_v6`.
+pub fn bind_udp(address: SocketAddr, only_v6: bool) -> std::io::Result<UdpSocket> {
+    let socket = new_socket(address, SocketType::DGRAM, only_v6)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Bind a TCP listener to `address`, to accept connections on
+///
+/// An IPv6 socket that is bound to the unspecified address, `[::]`, accepts IPv4 traffic as well,
+/// on IPv4-mapped addresses, unless `only_v6` is set.
+/// This is set explicitly, instead of following the system-wide default.
+pub fn bind_tcp(address: SocketAddr, only_v6: bool) -> std::io::Result<TcpListener> {
+    let socket = new_socket(address, SocketType::STREAM, only_v6)?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// A non-blocking socket bound to `address`
+fn new_socket(address: SocketAddr, ty: SocketType, only_v6: bool) -> std::io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(address), ty, None)?;
+    if address.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+    if ty == SocketType::STREAM {
+        // Allows restarting while connections of the previous run are in TIME_WAIT, like tokio does.
+        socket.set_reuse_address(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    Ok(socket)
+}
+
+/// The transport that a query was received over, and that its response is sent back over
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transport {
+    /// A single datagram, whose size is limited
+    Udp,
+
+    /// A message on a TCP connection, prefixed with its length
+    Tcp,
+}
+
+/// Handles queries, according to the settings of the server
+#[derive(Debug)]
+pub struct Handler {
+    /// How queries are answered
+    mode: Mode,
+
+    /// Upstream resolvers to forward queries to, in the forwarding mode
+    upstreams: Upstreams,
+
+    /// Upstream resolvers for the conditional forwarding rules, by domain, from the longest domain
+    conditional: Vec<(Name, Upstreams)>,
+
+    /// Zones that we are authoritative for, from the one with the longest origin
+    zones: Vec<Zone>,
+
+    /// The recursive resolver, in the resolver mode with root hints
+    recursor: Option<Recursor>,
+
+    /// Time that resolving a single question recursively may take, with the aliases in its answer
+    resolution_deadline: Duration,
+
+    /// Forwarded answers, and what we learn while resolving recursively, unless the cache is disabled
+    cache: Option<Cache>,
+
+    /// The largest UDP payload that we are willing to send or receive, with EDNS(0)
+    max_udp_payload: u16,
+
+    /// The address that `A` queries are answered with, in the resolver mode without root hints
+    address: Ipv4Addr,
+
+    /// Time-to-live of the answers, in the resolver mode without root hints
+    ttl: u32,
+}
+
+impl Handler {
+    /// A handler that answers queries according to `config`
+    ///
+    /// `max_udp_payload` is raised to 512 bytes, the limit without EDNS(0), if it's smaller.
+    pub fn new(config: &Config) -> Result<Self, ConfigError> {
+        let max_udp_payload = config
+            .server
+            .max_udp_payload
+            .max(Edns::MIN_UDP_PAYLOAD_SIZE);
+        let mut conditional: Vec<(Name, Upstreams)> = config
+            .forward
+            .iter()
+            .map(|rule| {
+                let upstreams = UpstreamsConfig {
+                    resolvers: rule.resolvers.clone(),
+                    weights: vec![],
+                    ..config.upstreams.clone()
+                };
+                (
+                    rule.domain.clone(),
+                    Upstreams::new(&upstreams, max_udp_payload),
+                )
+            })
+            .collect();
+        conditional.sort_by_key(|(domain, _upstreams)| Reverse(domain.labels().count()));
+
+        let mut zones = config
+            .zone
+            .iter()
+            .map(Zone::load)
+            .collect::<Result<Vec<_>, _>>()?;
+        zones.sort_by_key(|zone| Reverse(zone.origin().labels().count()));
+        if let Some(zone) = zones.windows(2).find(|w| w[0].origin() == w[1].origin()) {
+            return Err(ConfigError::Invalid(
+                "zone",
+                format!("there is more than one zone for {}", zone[0].origin()),
+            ));
+        }
+
+        let mode = config.mode()?;
+        let recursor = match mode {
+            Mode::Resolve => Recursor::new(&config.resolve, max_udp_payload)?,
+            Mode::Forward => None,
+        };
+
+        Ok(Self {
+            mode,
+            upstreams: Upstreams::new(&config.upstreams, max_udp_payload),
+            conditional,
+            zones,
+            recursor,
+            resolution_deadline: Duration::from_millis(config.resolve.deadline_ms),
+            cache: Cache::new(&config.cache),
+            max_udp_payload,
+            address: config.resolve.address,
+            ttl: config.resolve.ttl,
+        })
+    }
+
+    /// Take over what `old`, the handler that this one replaces, has learned: the cached answers,
+    /// the statistics and the health of the upstream resolvers that are still there,
+    /// and the statistics of the name servers
+    pub fn inherit(&self, old: &Handler) {
+        if let (Some(cache), Some(old_cache)) = (&self.cache, &old.cache) {
+            cache.inherit(old_cache);
+        }
+        self.upstreams.inherit(&old.upstreams);
+        for (domain, upstreams) in &self.conditional {
+            if let Some((_, old_upstreams)) = old.conditional.iter().find(|(d, _)| d == domain) {
+                upstreams.inherit(old_upstreams);
+            }
+        }
+        if let (Some(recursor), Some(old_recursor)) = (&self.recursor, &old.recursor) {
+            recursor.inherit(old_recursor);
+        }
+    }
+
+    /// The largest UDP payload that we are willing to send or receive
+    pub fn max_udp_payload(&self) -> u16 {
+        self.max_udp_payload
+    }
+
+    /// Handle a single query, `buf`, received from `source`, and send the response back to it
+    ///
+    /// The response is sent through `udp_socket`, which is the socket that the query was received on.
+    pub async fn handle_request(
+        &self,
+        udp_socket: &UdpSocket,
+        buf: &[u8],
+        source: SocketAddr,
+    ) -> Result<(), ConnectionError> {
+        let r_buf = self.respond(buf, Transport::Udp).await?;
+
+        let written = udp_socket
+            .send_to(&r_buf, source)
+            .await
+            .map_err(ConnectionError::SendError)?;
+        info!("-> Sent {} bytes back to {}", written, source);
+
+        Ok(())
+    }
+
+    /// Handle all queries that arrive on a TCP connection, `stream`, from `source`
+    ///
+    /// Every message is prefixed with its length, as a two-byte integer.
+    /// Queries are pipelined: each of them is handled as soon as it arrives, without waiting for
+    /// the previous ones to be answered, and responses are sent back in the order in which they're ready.
+    /// The connection is closed once the client closes it, when it's idle for
+    /// [`TCP_IDLE_TIMEOUT_MS`], or when `shutdown` becomes true, after the outstanding queries
+    /// are answered; the function returns only then.
+    /// It's only idle while no queries are outstanding, so the timer starts over with the last response.
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc7766#section-6.2.1.1
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc7766#section-6.2.3
+    pub async fn handle_tcp_connection(
+        self: Arc<Self>,
+        stream: TcpStream,
+        source: SocketAddr,
+        mut shutdown: watch::Receiver<bool>,
+    ) -> Result<(), ConnectionError> {
+        let (mut reader, writer) = stream.into_split();
+        let writer = Arc::new(Mutex::new(writer));
+        let in_flight = Arc::new(Semaphore::new(MAX_TCP_PIPELINED_QUERIES));
+        let idle_timeout = Duration::from_millis(TCP_IDLE_TIMEOUT_MS);
+
+        let result = loop {
+            let mut len = [0u8; 2];
+            tokio::select! {
+                read = reader.read_exact(&mut len) => match read {
+                    Ok(_) => {}
+                    Err(e) if e.kind() == ErrorKind::UnexpectedEof => break Ok(()),
+                    Err(e) => break Err(ConnectionError::RecvError(e)),
+                },
+                _ = idle(&in_flight, idle_timeout) => {
+                    debug!("Closing the idle connection from {}", source);
+                    break Ok(());
+                }
+                _ = shutdown.wait_for(|&shutdown| shutdown) => {
+                    debug!("Closing the connection from {} to shut down", source);
+                    break Ok(());
+                }
+            }
+
+            let mut buf = vec![0u8; u16::from_be_bytes(len) as usize];
+            match timeout(idle_timeout, reader.read_exact(&mut buf)).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => break Err(ConnectionError::RecvError(e)),
+                Err(_) => break Err(ConnectionError::RecvError(ErrorKind::TimedOut.into())),
+            }
+            info!("<= Received {} bytes from {} over TCP", buf.len(), source);
+
+            let permit = match in_flight.clone().acquire_owned().await {
+                Ok(permit) => permit,
+                Err(e) => break Err(ConnectionError::Other(e.into())),
+            };
+            let handler = self.clone();
+            let writer = writer.clone();
+            tokio::spawn(async move {
+                let r_buf = match handler.respond(&buf, Transport::Tcp).await {
+                    Ok(r_buf) => r_buf,
+                    Err(e) => {
+                        warn!("{e}");
+                        return;
+                    }
+                };
+                let Ok(len) = u16::try_from(r_buf.len()) else {
+                    warn!(
+                        "Response of {} bytes doesn't fit in a TCP message",
+                        r_buf.len()
+                    );
+                    return;
+                };
+
+                let mut writer = writer.lock().await;
+                let written = async {
+                    writer.write_all(&len.to_be_bytes()).await?;
+                    writer.write_all(&r_buf).await
+                };
+                match written.await {
+                    Ok(()) => info!("-> Sent {} bytes back to {} over TCP", r_buf.len(), source),
+                    Err(e) => warn!("{}", ConnectionError::SendError(e)),
+                }
+                drop(permit);
+            });
+        };
+
+        // The connection is done only once the queries in flight on it are answered.
+        let _ = in_flight
+            .acquire_many(MAX_TCP_PIPELINED_QUERIES as u32)
+            .await;
+
+        result
+    }
+
+    /// Produce the response to the query `buf`
+    ///
+    /// Every query whose header can be read gets a response, with the query's ID echoed back:
+    /// - FORMERR if the rest of the query can't be read,
+    /// - NOTIMP if the kind of query isn't supported,
+    /// - BADVERS if the query uses a version of EDNS that isn't supported,
+    /// - SERVFAIL if resolving it fails, e.g., because the upstream resolver can't be reached.
+    ///
+    /// Other messages, which can't be told apart from noise, are dropped, and so are responses,
+    /// so that we never answer an answer.
+    ///
+    /// A query with EDNS(0) gets a response with EDNS(0).
+    ///
+    /// A response that doesn't fit in a UDP message, whose size is limited to 512 bytes, or to
+    /// the client's payload size with EDNS(0), is truncated; the client then retries over TCP.
+    async fn respond(&self, buf: &[u8], transport: Transport) -> Result<Vec<u8>, ConnectionError> {
+        //
+        // <== Query
+        //
+
+        let (_rest, qheader) = Header::from_bytes((buf, 0))?;
+
+        if qheader.qr != Qr::Query {
+            return Err(ConnectionError::NotAQuery(qheader.id));
+        }
+
+        let query = match Message::from_bytes((buf, 0)) {
+            Ok((_rest, query)) => query,
+            Err(e) if qheader.opcode == OpCode::Query => {
+                warn!("Malformed query {}: {}", qheader.id, e);
+                return error_response(&qheader, vec![], ResponseCode::FormatError, None);
+            }
+            Err(_) => {
+                warn!(
+                    "Unsupported opcode {:?} in query {}",
+                    qheader.opcode, qheader.id
+                );
+                return error_response(&qheader, vec![], ResponseCode::NotImplemented, None);
+            }
+        };
+        let qedns = match query.edns() {
+            Ok(qedns) => qedns,
+            Err(e) => {
+                warn!("Malformed query {}: {}", qheader.id, e);
+                return error_response(&qheader, vec![], ResponseCode::FormatError, None);
+            }
+        };
+        let redns = qedns.as_ref().map(|qedns| self.response_edns(qedns));
+        let max_len = match (transport, &qedns) {
+            (Transport::Udp, None) => BUFFER_LEN,
+            (Transport::Udp, Some(qedns)) => qedns
+                .udp_payload_size
+                .clamp(Edns::MIN_UDP_PAYLOAD_SIZE, self.max_udp_payload)
+                as usize,
+            (Transport::Tcp, _) => u16::MAX as usize,
+        };
+
+        if qheader.opcode != OpCode::Query {
+            warn!(
+                "Unsupported opcode {:?} in query {}",
+                qheader.opcode, qheader.id
+            );
+            return error_response(&qheader, vec![], ResponseCode::NotImplemented, redns);
+        }
+
+        if let (Some(qedns), Some(mut redns)) = (&qedns, redns.clone()) {
+            if qedns.version != Edns::VERSION {
+                warn!(
+                    "Unsupported EDNS version {} in query {}",
+                    qedns.version, qheader.id
+                );
+                let mut rcode = ResponseCode::NoError;
+                redns.set_rcode(&mut rcode, Edns::BADVERS);
+                return error_response(&qheader, query.question, rcode, Some(redns));
+            }
+        }
+
+        if query.question.is_empty() {
+            warn!("Query {} has no questions", qheader.id);
+            return error_response(&qheader, vec![], ResponseCode::FormatError, redns);
+        }
+
+        //
+        // --> Response
+        //
+
+        match self.resolve(&qheader, &query.question, redns.clone()).await {
+            Ok(rmsg) => {
+                let r_buf = rmsg.to_bytes()?;
+                if r_buf.len() <= max_len {
+                    return Ok(r_buf);
+                }
+                debug!(
+                    "Truncating the response to query {}, as its {} bytes don't fit in {}",
+                    qheader.id,
+                    r_buf.len(),
+                    max_len
+                );
+                Ok(truncate(rmsg).to_bytes()?)
+            }
+            Err(e) => {
+                warn!("Failed to resolve query {}: {}", qheader.id, e);
+                error_response(&qheader, query.question, ResponseCode::ServerFailure, redns)
+            }
+        }
+    }
+
+    /// EDNS(0) data of a response to a query with `qedns`
+    ///
+    /// We advertise our own payload size, and copy the DO flag, as we pass on DNSSEC records as they are.
+    fn response_edns(&self, qedns: &Edns) -> Edns {
+        let mut redns = Edns::new(self.max_udp_payload);
+        redns.dnssec_ok = qedns.dnssec_ok;
+        redns
+    }
+
+    /// The zone that answers `question` authoritatively, if there is one
+    ///
+    /// It's the zone with the longest origin that `qname` is in, unless a conditional forwarding rule
+    /// for a domain below the origin covers `qname`. Zones are in the Internet class.
+    fn zone_for(&self, question: &Question) -> Option<&Zone> {
+        if question.qclass != Qclass::IN {
+            return None;
+        }
+        let qname = &question.qname;
+        let zone = self
+            .zones
+            .iter()
+            .find(|zone| qname.is_subdomain_of(zone.origin()))?;
+        let forwarded = self.conditional.iter().any(|(domain, _upstreams)| {
+            qname.is_subdomain_of(domain)
+                && domain.labels().count() > zone.origin().labels().count()
+        });
+        (!forwarded).then_some(zone)
+    }
+
+    /// The upstream resolvers to forward a question about `qname` to, if it's forwarded
+    ///
+    /// A conditional forwarding rule for a domain that `qname` is in wins, the one with the longest
+    /// domain if there are several; other questions go to the default resolvers in the forwarding mode.
+    fn upstreams_for(&self, qname: &Name) -> Option<&Upstreams> {
+        self.conditional
+            .iter()
+            .find(|(domain, _upstreams)| qname.is_subdomain_of(domain))
+            .map(|(_domain, upstreams)| upstreams)
+            .or((self.mode == Mode::Forward).then_some(&self.upstreams))
+    }
+
+    /// The recursive resolver, if we resolve `question` recursively
+    ///
+    /// We do in the resolver mode with root hints, for questions in the Internet class
+    /// that aren't forwarded.
+    fn recursor_for(&self, question: &Question) -> Option<&Recursor> {
+        let recursor = self.recursor.as_ref()?;
+        (question.qclass == Qclass::IN && self.upstreams_for(&question.qname).is_none())
+            .then_some(recursor)
+    }
+
+    /// Resolve the `questions` of a query, and return the response
+    ///
+    /// Every question is answered from a zone, as [`Handler::zone_for`] decides, forwarded,
+    /// as [`Handler::upstreams_for`] decides, or resolved by us, recursively if
+    /// [`Handler::recursor_for`] says so.
+    /// `redns` is EDNS(0) data to add to the response, if the query had it.
+    async fn resolve(
+        &self,
+        qheader: &Header,
+        questions: &[Question],
+        redns: Option<Edns>,
+    ) -> Result<Message, ConnectionError> {
+        let mut rheader = response_header(qheader, ResponseCode::NoError);
+
+        // Response data
+        let mut answers: Vec<ResourceRecord> = vec![];
+        let mut authority: Vec<ResourceRecord> = vec![];
+        let mut additional: Vec<ResourceRecord> = vec![];
+
+        // The response is authoritative, and recursion is available, only if it is so for every question.
+        rheader.aa = 1;
+        rheader.ra = 1;
+        let mut extended_rcode = 0;
+        let dnssec_ok = redns.as_ref().is_some_and(|redns| redns.dnssec_ok);
+        for question in questions {
+            // Resolving the question recursively, and the aliases in its answer, has a single deadline.
+            let deadline = Instant::now() + self.resolution_deadline;
+            let (mut lookup, recursion) = if let Some(zone) = self.zone_for(question) {
+                // We are authoritative for this question, unless it's delegated,
+                // in which case we refer the client to the name servers of the subdomain.
+                let lookup = zone.lookup(&question.qname, question.qtype);
+                (
+                    lookup,
+                    self.mode == Mode::Forward || self.recursor.is_some(),
+                )
+            } else if let Some(upstreams) = self.upstreams_for(&question.qname) {
+                // We are a forwarding DNS server (a DNS forwarder) for this question.
+                // Let's forward it to a DNS resolver and collect the response that we get from it,
+                // unless we still have it in the cache.
+                // Resolvers generally don't accept more than one question in a query,
+                // so we ask them one by one and merge their answers.
+                let cached = self.cache.as_ref().and_then(|c| c.get(question, dnssec_ok));
+                let answer = match cached {
+                    Some(answer) => {
+                        debug!("Answering {} from the cache", question.qname);
+                        answer
+                    }
+                    None => {
+                        let r_buf = upstreams.query(question, dnssec_ok).await?;
+                        let (_rest, answer) = Message::from_bytes((&r_buf, 0))?;
+                        if let Some(cache) = &self.cache {
+                            cache.insert(question, dnssec_ok, &answer);
+                        }
+                        answer
+                    }
+                };
+                let recursion = answer.header.ra == 1;
+                (lookup_of(answer), recursion)
+            } else if let Some(recursor) = self.recursor_for(question) {
+                // We are a recursive resolver for this question.
+                // Let's ask the name servers ourselves, from the root servers down, unless we still
+                // have the answer in the cache. We aren't authoritative for their answers.
+                let answer = recursor
+                    .resolve(question, dnssec_ok, self.cache.as_ref(), deadline)
+                    .await?;
+                let mut lookup = lookup_of(answer);
+                lookup.authoritative = false;
+                (lookup, true)
+            } else {
+                // We are the DNS resolver, but without root hints, so we resolve the question ourselves.
+                // We only know of host addresses in the Internet class; other types have no data.
+                let mut lookup = Lookup {
+                    rcode: ResponseCode::NoError,
+                    extended_rcode: 0,
+                    authoritative: false,
+                    answer: vec![],
+                    authority: vec![],
+                    additional: vec![],
+                };
+                if question.qclass != Qclass::IN {
+                    lookup.rcode = ResponseCode::NotImplemented;
+                } else if question.qtype == Qtype::A {
+                    lookup.answer.push(ResourceRecord::new(
+                        question.qname.clone(),
+                        Type::A,
+                        Class::IN,
+                        self.ttl,
+                        RData::A(self.address),
+                    ));
+                }
+                (lookup, false)
+            };
+            self.chase(question, dnssec_ok, deadline, &mut lookup).await;
+
+            if rheader.rcode == ResponseCode::NoError && extended_rcode == 0 {
+                rheader.rcode = lookup.rcode;
+                extended_rcode = lookup.extended_rcode;
+            }
+            rheader.aa &= u8::from(lookup.authoritative);
+            rheader.ra &= u8::from(recursion);
+            answers.extend(lookup.answer);
+            authority.extend(lookup.authority);
+            additional.extend(lookup.additional);
+        }
+        match redns {
+            Some(mut redns) => {
+                redns.extended_rcode = extended_rcode;
+                additional.push(redns.to_record());
+            }
+            // A client without EDNS(0) can't be told an extended RCODE.
+            None if extended_rcode != 0 => rheader.rcode = ResponseCode::ServerFailure,
+            None => {}
+        }
+        rheader.qdcount = questions.len() as u16;
+        rheader.ancount = answers.len() as u16;
+        rheader.nscount = authority.len() as u16;
+        rheader.arcount = additional.len() as u16;
+
+        let rmsg = Message {
+            header: rheader,
+            question: questions.to_vec(),
+            answer: answers,
+            authority,
+            additional,
+        };
+        debug!("-> {:?}", rmsg);
+
+        Ok(rmsg)
+    }
+
+    /// Follow the chain of aliases in `lookup`, the answer to `question`
+    ///
+    /// While the answer ends with a CNAME record, the records of its target are appended to it,
+    /// from one of our zones, from the cache, or, if [`Handler::recursor_for`] says so, from the name
+    /// servers of the target, until a target can't be found in any of them.
+    /// The response code and the authority section become those of the last target, so a chain that
+    /// ends at a name that doesn't exist gets NXDOMAIN. The AA bit stays that of the question's name.
+    /// Chains that loop, or that are longer than [`MAX_CNAME_CHAIN`], are cut off, and so are those
+    /// whose targets can't be resolved before the `deadline` of the question.
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc1034#section-4.3.2
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc6604#section-2
+    async fn chase(
+        &self,
+        question: &Question,
+        dnssec_ok: bool,
+        deadline: Instant,
+        lookup: &mut Lookup,
+    ) {
+        // Questions about CNAME records, or about all records, are answered by the aliases themselves.
+        if matches!(question.qtype, Qtype::CNAME | Qtype::ANY) {
+            return;
+        }
+
+        let mut chain = vec![question.qname.clone()];
+        while let Some(target) = cname_target(&lookup.answer, &chain[chain.len() - 1]) {
+            if chain.contains(&target) {
+                debug!("The aliases of {} form a loop", question.qname);
+                return;
+            }
+            if chain.len() > MAX_CNAME_CHAIN {
+                debug!(
+                    "The aliases of {} form a chain longer than {}",
+                    question.qname, MAX_CNAME_CHAIN
+                );
+                return;
+            }
+            chain.push(target.clone());
+            if lookup.answer.iter().any(|rr| rr.name == target) {
+                // Already followed, as upstream resolvers do
+                continue;
+            }
+
+            let next = Question::new(target, question.qtype, question.qclass);
+            let found = if let Some(zone) = self.zone_for(&next) {
+                zone.lookup(&next.qname, next.qtype)
+            } else if let Some(recursor) = self.recursor_for(&next) {
+                match recursor
+                    .resolve(&next, dnssec_ok, self.cache.as_ref(), deadline)
+                    .await
+                {
+                    Ok(answer) => lookup_of(answer),
+                    Err(e) => {
+                        debug!("Failed to resolve {}: {}", next.qname, e);
+                        return;
+                    }
+                }
+            } else {
+                match self.cache.as_ref().and_then(|c| c.get(&next, dnssec_ok)) {
+                    Some(answer) => lookup_of(answer),
+                    None => return,
+                }
+            };
+            lookup.rcode = found.rcode;
+            lookup.extended_rcode = found.extended_rcode;
+            lookup.answer.extend(found.answer);
+            lookup.authority = found.authority;
+            lookup.additional.extend(found.additional);
+        }
+    }
+}
+
+/// Wait until a TCP connection has been idle for `idle_timeout`, that is, without any of
+/// the permits of `in_flight` taken by a query, which holds one until it's answered
+async fn idle(in_flight: &Semaphore, idle_timeout: Duration) {
+    let _ = in_flight
+        .acquire_many(MAX_TCP_PIPELINED_QUERIES as u32)
+        .await;
+    sleep(idle_timeout).await;
+}
+
+/// The target of the CNAME record that `name` owns among `records`, if there is one
+fn cname_target(records: &[ResourceRecord], name: &Name) -> Option<Name> {
+    records.iter().find_map(|rr| match &rr.rdata {
+        RData::CNAME(target) if rr.name == *name => Some(target.clone()),
+        _ => None,
+    })
+}
+
+/// The sections of `answer`, a response of an upstream resolver, or one from the cache
+///
+/// The resolver's OPT record is between it and us, so it's left out, but for the upper bits
+/// of an extended RCODE that it carries, which the client gets in our OPT record.
+fn lookup_of(answer: Message) -> Lookup {
+    let extended_rcode = match answer.edns() {
+        Ok(Some(edns)) => edns.extended_rcode,
+        _ => 0,
     };
+    Lookup {
+        rcode: answer.header.rcode,
+        extended_rcode,
+        authoritative: answer.header.aa == 1,
+        answer: answer.answer,
+        authority: answer.authority,
+        additional: answer
+            .additional
+            .into_iter()
+            .filter(|rr| rr.type_ != Type::OPT)
+            .collect(),
+    }
+}
 
-    let rheader = Header {
+/// Header of a response to the query with the header `qheader`, without any records
+fn response_header(qheader: &Header, rcode: ResponseCode) -> Header {
+    Header {
         id: qheader.id,
         qr: Qr::Response,
         opcode: qheader.opcode,
@@ -57,140 +714,94 @@ pub async fn handle_request(
         ra: 0,
         z: 0,
         rcode,
-        qdcount: qheader.qdcount,
-        ancount: qheader.qdcount,
+        qdcount: 0,
+        ancount: 0,
         nscount: 0,
         arcount: 0,
-    };
+    }
+}
 
-    // Response data
-    let mut rdata: Vec<[u8; 4]> = vec![];
-
-    if let Some(resolver) = resolver {
-        // We are a forwarding DNS server (a DNS forwarder).
-        // Let's forward DNS queries to a DNS resolver and collect the responses that we get from it.
-        for question in &questions {
-            let mut q_buf = BytesMut::from(&buf[0..received]);
-            q_buf[0..12].copy_from_slice(&buf[..12]);
-            // Their test suite doesn't support OpCode::InverseQuery in this case, so we have to hack this byte,
-            // q_buf[2], to OpCode::Query, in order for that test to pass!
-            q_buf[2] = 1;
-            q_buf[4] = 0; // qheader.qdcount[hi]
-            q_buf[5] = 1; // qheader.qdcount[lo]
-            q_buf[12..12 + question.qname.len()].copy_from_slice(&question.qname);
-            q_buf[12 + question.qname.len()..][..4].copy_from_slice(&[0, 1, 0, 1]); // Append Qtype & Qclass.
-
-            // Send a Question message
-            udp_socket
-                .send_to(&q_buf, resolver)
-                .await
-                .map_err(ConnectionError::SendError)?;
+/// The response `rmsg` cut down to its header and question section, with the TC bit set
+///
+/// The OPT record is kept, so that the client still learns our payload size.
+fn truncate(mut rmsg: Message) -> Message {
+    rmsg.answer.clear();
+    rmsg.authority.clear();
+    rmsg.additional.retain(|rr| rr.type_ == Type::OPT);
 
-            // Receive an Answer message
-            let mut r_buf = [0u8; BUFFER_LEN];
-            udp_socket
-                .recv_from(&mut r_buf)
-                .await
-                .map_err(ConnectionError::RecvError)?;
+    rmsg.header.tc = 1;
+    rmsg.header.ancount = 0;
+    rmsg.header.nscount = 0;
+    rmsg.header.arcount = rmsg.additional.len() as u16;
 
-            let (_rest, answer) = Message::from_bytes((&r_buf, 0))?;
-            let r =
-                <[u8; 4]>::try_from(answer.answer[0].rdata.clone()).expect("Try from slice failed");
-            rdata.push(r);
-        }
-    } else {
-        // We are the DNS resolver, so we resolve the DNS queries ourselves.
-        rdata = vec![ARBITRARY_IPV4; questions.len()];
-    }
+    rmsg
+}
 
-    let answers = zip(questions.iter(), rdata.iter())
-        .map(|(q, r)| ResourceRecord::new(q.qname.clone(), Type::A, Class::IN, TTL, Vec::from(r)))
-        .collect::<Vec<_>>();
+/// The wire format of a response with the response code `rcode` and no records, which echoes `questions`
+///
+/// `redns` is EDNS(0) data to add to the response, if the query had it.
+fn error_response(
+    qheader: &Header,
+    questions: Vec<Question>,
+    rcode: ResponseCode,
+    redns: Option<Edns>,
+) -> Result<Vec<u8>, ConnectionError> {
+    let mut rheader = response_header(qheader, rcode);
+    rheader.qdcount = questions.len() as u16;
+
+    let additional: Vec<ResourceRecord> = redns.iter().map(Edns::to_record).collect();
+    rheader.arcount = additional.len() as u16;
 
     let rmsg = Message {
         header: rheader,
         question: questions,
-        answer: answers,
+        answer: vec![],
+        authority: vec![],
+        additional,
     };
     debug!("-> {:?}", rmsg);
 
-    let mut buf = [0u8; BUFFER_LEN];
-    let wrote = rmsg.to_slice(&mut buf)?;
-    let written = udp_socket
-        .send_to(&buf[..wrote], source)
-        .await
-        .map_err(ConnectionError::SendError)?;
-    info!("-> Sent {} bytes back to {}", written, source);
-
-    Ok(())
+    Ok(rmsg.to_bytes()?)
 }
 
 /// Parse the Question section
-fn parse_question(
+///
+/// `rest` is the part of `buf` that follows the header.
+/// Questions are read from the whole message, `buf`, so that compressed names can be followed.
+pub(crate) fn parse_question(
     buf: &[u8],
     rest: &[u8],
     qheader: &Header,
     questions: &mut Vec<Question>,
 ) -> Result<(), ConnectionError> {
-    // The first question is never compressed, so using "deku" is always okay for the first question.
-    let (rest, question) = Question::from_bytes((rest, 0))?;
-    let mut rest = rest.0;
-    questions.push(question);
-
-    for _qi in 1..qheader.qdcount {
-        let (r, question) = match Question::from_bytes((rest, 0)) {
-            Ok((r, q)) => (r, q), // Uncompressed question
-
-            Err(e) => {
-                // Compressed question
-                trace!("Compressed question");
-                trace!("error: {}", e); // DekuError::Parse
-                let mut qname = vec![];
-
-                // Iterate over bytes until a byte begins with 0b11, meaning it's >= 192, i.e., >= 0xc0.
-                let mut offset_hi = 0u8;
-                let mut bi = 0usize;
-                for b in rest {
-                    bi += 1;
-                    if b == &0 {
-                        return Err(ConnectionError::ZeroByte);
-                    } else if b < &192 {
-                        qname.push(*b);
-                    } else {
-                        offset_hi &= 0x3f;
-                        break;
-                    }
-                }
-                let offset_lo = rest[bi];
-                bi += 1;
-                let jump = u16::from_be_bytes([offset_hi, offset_lo]);
-
-                // Update qname.
-                let (_r, qq) = Question::from_bytes((buf, 8 * jump as usize))?;
-                qname.extend_from_slice(&qq.qname);
+    let mut cursor = Cursor::new(buf);
+    cursor.set_position((buf.len() - rest.len()) as u64);
+    let mut reader = Reader::new(&mut cursor);
 
-                let qtype = u16::from_be_bytes([rest[bi], rest[bi + 1]]);
-                bi += 2;
-                let qclass = u16::from_be_bytes([rest[bi], rest[bi + 1]]);
-                bi += 2;
-
-                let r = (&rest[bi..], 0usize);
-                let q = Question::new(qname, qtype.try_into()?, qclass.try_into()?);
-
-                (r, q)
-            }
-        };
-        rest = r.0;
-        questions.push(question);
+    for _qi in 0..qheader.qdcount {
+        questions.push(Question::from_reader_with_ctx(&mut reader, ())?);
     }
+
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::conn::parse_question;
-    use crate::message::{Header, Qclass, Qtype};
-    use deku::DekuContainerRead;
+    use crate::config::{Config, ForwardRule, ResolveConfig, UpstreamsConfig, ZoneConfig};
+    use crate::conn::{bind_udp, parse_question, Handler, Transport};
+    use crate::errors::ConfigError;
+    use crate::fixtures::TempDir;
+    use crate::message::{Class, Edns, Header, Message, Name, OpCode, Qclass, Qr, Qtype, RData};
+    use crate::message::{ResourceRecord, ResponseCode, Type};
+    use deku::{DekuContainerRead, DekuContainerWrite};
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream, UdpSocket};
+    use tokio::sync::watch;
 
     #[test]
     fn one_question_uncompressed() {
@@ -215,7 +826,7 @@ mod tests {
                 3u8, 97, 98, 99, 17, 108, 111, 110, 103, 97, 115, 115, 100, 111, 109, 97, 105, 110,
                 110, 97, 109, 101, 3, 99, 111, 109, 0
             ],
-            questions[0].qname
+            questions[0].qname.as_bytes()
         );
         assert_eq!(Qtype::A, questions[0].qtype);
         assert_eq!(Qclass::IN, questions[0].qclass);
@@ -252,7 +863,7 @@ mod tests {
                 3u8, 97, 98, 99, 17, 108, 111, 110, 103, 97, 115, 115, 100, 111, 109, 97, 105, 110,
                 110, 97, 109, 101, 3, 99, 111, 109, 0
             ],
-            questions[0].qname
+            questions[0].qname.as_bytes()
         );
         assert_eq!(Qtype::A, questions[0].qtype);
         assert_eq!(Qclass::IN, questions[0].qclass);
@@ -262,7 +873,7 @@ mod tests {
                 3u8, 100, 101, 102, 17, 108, 111, 110, 103, 97, 115, 115, 100, 111, 109, 97, 105,
                 110, 110, 97, 109, 101, 3, 99, 111, 109, 0
             ],
-            questions[1].qname
+            questions[1].qname.as_bytes()
         );
         assert_eq!(Qtype::NS, questions[1].qtype);
         assert_eq!(Qclass::IN, questions[1].qclass);
@@ -272,7 +883,7 @@ mod tests {
                 3u8, 103, 104, 105, 17, 108, 111, 110, 103, 97, 115, 115, 100, 111, 109, 97, 105,
                 110, 110, 97, 109, 101, 3, 99, 111, 109, 0
             ],
-            questions[2].qname
+            questions[2].qname.as_bytes()
         );
         assert_eq!(Qtype::MX, questions[2].qtype);
         assert_eq!(Qclass::IN, questions[2].qclass);
@@ -307,7 +918,7 @@ mod tests {
                 3u8, 97, 98, 99, 17, 108, 111, 110, 103, 97, 115, 115, 100, 111, 109, 97, 105, 110,
                 110, 97, 109, 101, 3, 99, 111, 109, 0
             ],
-            questions[0].qname
+            questions[0].qname.as_bytes()
         );
         assert_eq!(Qtype::A, questions[0].qtype);
         assert_eq!(Qclass::IN, questions[0].qclass);
@@ -317,7 +928,7 @@ mod tests {
                 3u8, 100, 101, 102, 17, 108, 111, 110, 103, 97, 115, 115, 100, 111, 109, 97, 105,
                 110, 110, 97, 109, 101, 3, 99, 111, 109, 0
             ],
-            questions[1].qname
+            questions[1].qname.as_bytes()
         );
         assert_eq!(Qtype::NS, questions[1].qtype);
         assert_eq!(Qclass::IN, questions[1].qclass);
@@ -327,7 +938,7 @@ mod tests {
                 3u8, 103, 104, 105, 17, 108, 111, 110, 103, 97, 115, 115, 100, 111, 109, 97, 105,
                 110, 110, 97, 109, 101, 3, 99, 111, 109, 0
             ],
-            questions[2].qname
+            questions[2].qname.as_bytes()
         );
         assert_eq!(Qtype::MX, questions[2].qtype);
         assert_eq!(Qclass::IN, questions[2].qclass);
@@ -354,15 +965,870 @@ mod tests {
 
         assert_eq!(4, questions.len());
 
-        assert_eq!(vec![2u8, 97, 97, 0], questions[0].qname); // "aa"
+        assert_eq!(vec![2u8, 97, 97, 0], questions[0].qname.as_bytes()); // "aa"
         assert_eq!(
             vec![1u8, 102, 3, 105, 115, 105, 4, 97, 114, 112, 97, 0], // "f.isi.arpa"
-            questions[1].qname
+            questions[1].qname.as_bytes()
         );
         assert_eq!(
             vec![3u8, 102, 111, 111, 1, 102, 3, 105, 115, 105, 4, 97, 114, 112, 97, 0], // "foo.f.isi.arpa"
-            questions[2].qname
+            questions[2].qname.as_bytes()
         );
-        assert_eq!(vec![4u8, 97, 114, 112, 97, 0], questions[3].qname); // "arpa"
+        assert_eq!(
+            vec![4u8, 97, 114, 112, 97, 0],
+            questions[3].qname.as_bytes()
+        ); // "arpa"
+    }
+
+    #[tokio::test]
+    async fn malformed_question_gets_format_error() {
+        // "abc." is cut short, right after its label.
+        let buf: [u8; 16] = [77, 77, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 3, 97, 98, 99];
+
+        let r_buf = Handler::new(&Config::default())
+            .unwrap()
+            .respond(&buf, Transport::Udp)
+            .await
+            .unwrap();
+        let (_rest, rmsg) = Message::from_bytes((&r_buf, 0)).unwrap();
+
+        assert_eq!(0x4d4d, rmsg.header.id);
+        assert_eq!(Qr::Response, rmsg.header.qr);
+        assert_eq!(1, rmsg.header.rd);
+        assert_eq!(ResponseCode::FormatError, rmsg.header.rcode);
+        assert!(rmsg.question.is_empty());
+        assert!(rmsg.answer.is_empty());
+    }
+
+    #[tokio::test]
+    async fn no_question_gets_format_error() {
+        let buf: [u8; 12] = [77, 77, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+        let r_buf = Handler::new(&Config::default())
+            .unwrap()
+            .respond(&buf, Transport::Udp)
+            .await
+            .unwrap();
+        let (_rest, rmsg) = Message::from_bytes((&r_buf, 0)).unwrap();
+
+        assert_eq!(0x4d4d, rmsg.header.id);
+        assert_eq!(ResponseCode::FormatError, rmsg.header.rcode);
+    }
+
+    #[tokio::test]
+    async fn unsupported_opcode_gets_not_implemented() {
+        // Opcode 5 (UPDATE)
+        let buf: [u8; 21] = [
+            77, 77, 0x28, 0, 0, 1, 0, 0, 0, 0, 0, 0, 3, 97, 98, 99, 0, 0, 1, 0, 1,
+        ];
+
+        let r_buf = Handler::new(&Config::default())
+            .unwrap()
+            .respond(&buf, Transport::Udp)
+            .await
+            .unwrap();
+        let (_rest, rmsg) = Message::from_bytes((&r_buf, 0)).unwrap();
+
+        assert_eq!(0x4d4d, rmsg.header.id);
+        assert_eq!(OpCode::Reserved(5), rmsg.header.opcode);
+        assert_eq!(ResponseCode::NotImplemented, rmsg.header.rcode);
+    }
+
+    #[tokio::test]
+    async fn responses_and_short_headers_are_dropped() {
+        let handler = Handler::new(&Config::default()).unwrap();
+
+        let response: [u8; 12] = [77, 77, 0x81, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert!(handler.respond(&response, Transport::Udp).await.is_err());
+
+        let short: [u8; 5] = [77, 77, 1, 0, 0];
+        assert!(handler.respond(&short, Transport::Udp).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn edns_query_gets_edns_response() {
+        let buf: [u8; 32] = [
+            77, 77, 1, 0, 0, 1, 0, 0, 0, 0, 0, 1, 3, 97, 98, 99, 0, 0, 1, 0, 1,
+            //
+            // OPT: payload size 4096, DO
+            0, 0, 41, 16, 0, 0, 0, 128, 0, 0, 0,
+        ];
+
+        let mut config = Config::default();
+        config.server.max_udp_payload = 1400;
+        let handler = Handler::new(&config).unwrap();
+        let r_buf = handler.respond(&buf, Transport::Udp).await.unwrap();
+        let (_rest, rmsg) = Message::from_bytes((&r_buf, 0)).unwrap();
+
+        assert_eq!(ResponseCode::NoError, rmsg.header.rcode);
+        assert_eq!(1, rmsg.answer.len());
+        assert_eq!(1, rmsg.header.arcount);
+        let redns = rmsg.edns().unwrap().unwrap();
+        assert_eq!(1400, redns.udp_payload_size);
+        assert_eq!(0, redns.extended_rcode);
+        assert!(redns.dnssec_ok);
+
+        // The same query without OPT
+        let mut buf = buf[..21].to_vec();
+        buf[11] = 0;
+
+        let r_buf = handler.respond(&buf, Transport::Udp).await.unwrap();
+        let (_rest, rmsg) = Message::from_bytes((&r_buf, 0)).unwrap();
+
+        assert!(rmsg.additional.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unsupported_edns_version_gets_badvers() {
+        let buf: [u8; 32] = [
+            77, 77, 1, 0, 0, 1, 0, 0, 0, 0, 0, 1, 3, 97, 98, 99, 0, 0, 1, 0, 1,
+            //
+            // OPT: payload size 4096, version 1
+            0, 0, 41, 16, 0, 0, 1, 0, 0, 0, 0,
+        ];
+
+        let r_buf = Handler::new(&Config::default())
+            .unwrap()
+            .respond(&buf, Transport::Udp)
+            .await
+            .unwrap();
+        let (_rest, rmsg) = Message::from_bytes((&r_buf, 0)).unwrap();
+
+        assert!(rmsg.answer.is_empty());
+        let redns = rmsg.edns().unwrap().unwrap();
+        assert_eq!(
+            Edns::BADVERS,
+            (redns.extended_rcode as u16) << 4 | u8::from(rmsg.header.rcode) as u16
+        );
+        assert_eq!(Edns::VERSION, redns.version);
+    }
+
+    #[tokio::test]
+    async fn two_opt_records_get_format_error() {
+        let buf: [u8; 43] = [
+            77, 77, 1, 0, 0, 1, 0, 0, 0, 0, 0, 2, 3, 97, 98, 99, 0, 0, 1, 0, 1, //
+            0, 0, 41, 16, 0, 0, 0, 0, 0, 0, 0, 0, 0, 41, 16, 0, 0, 0, 0, 0, 0, 0,
+        ];
+
+        let r_buf = Handler::new(&Config::default())
+            .unwrap()
+            .respond(&buf, Transport::Udp)
+            .await
+            .unwrap();
+        let (_rest, rmsg) = Message::from_bytes((&r_buf, 0)).unwrap();
+
+        assert_eq!(ResponseCode::FormatError, rmsg.header.rcode);
+        assert!(rmsg.additional.iter().all(|rr| rr.type_ != Type::OPT));
+    }
+
+    /// A query with `n` questions for "abcdefghij.example" A IN
+    fn many_questions(n: u16) -> Vec<u8> {
+        let mut buf = vec![77, 77, 1, 0];
+        buf.extend(n.to_be_bytes());
+        buf.extend([0, 0, 0, 0, 0, 0]);
+        for _ in 0..n {
+            buf.extend(b"\x0aabcdefghij\x07example\x00\x00\x01\x00\x01");
+        }
+        buf
+    }
+
+    #[tokio::test]
+    async fn large_udp_response_is_truncated() {
+        let handler = Handler::new(&Config::default()).unwrap();
+        let buf = many_questions(30);
+
+        let r_buf = handler.respond(&buf, Transport::Udp).await.unwrap();
+        let (_rest, rmsg) = Message::from_bytes((&r_buf, 0)).unwrap();
+
+        assert!(r_buf.len() <= 512);
+        assert_eq!(1, rmsg.header.tc);
+        assert_eq!(30, rmsg.question.len());
+        assert!(rmsg.answer.is_empty());
+
+        let r_buf = handler.respond(&buf, Transport::Tcp).await.unwrap();
+        let (_rest, rmsg) = Message::from_bytes((&r_buf, 0)).unwrap();
+
+        assert_eq!(0, rmsg.header.tc);
+        assert_eq!(30, rmsg.answer.len());
+    }
+
+    #[tokio::test]
+    async fn pipelined_tcp_queries() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let handler = Arc::new(Handler::new(&Config::default()).unwrap());
+        let (_shutdown_tx, shutdown) = watch::channel(false);
+        tokio::spawn(async move {
+            let (stream, source) = listener.accept().await.unwrap();
+            handler
+                .handle_tcp_connection(stream, source, shutdown)
+                .await
+                .unwrap();
+        });
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        for n in [1u16, 2] {
+            let buf = many_questions(n);
+            stream
+                .write_all(&(buf.len() as u16).to_be_bytes())
+                .await
+                .unwrap();
+            stream.write_all(&buf).await.unwrap();
+        }
+
+        let mut answers = vec![];
+        for _ in 0..2 {
+            let mut len = [0u8; 2];
+            stream.read_exact(&mut len).await.unwrap();
+            let mut r_buf = vec![0u8; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut r_buf).await.unwrap();
+            let (_rest, rmsg) = Message::from_bytes((&r_buf, 0)).unwrap();
+            answers.push(rmsg.answer.len());
+        }
+        answers.sort();
+        assert_eq!(vec![1, 2], answers);
+
+        stream.shutdown().await.unwrap();
+        let mut rest = vec![];
+        assert_eq!(0, stream.read_to_end(&mut rest).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn tcp_connection_closes_on_shutdown() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let handler = Arc::new(Handler::new(&Config::default()).unwrap());
+        let (shutdown_tx, shutdown) = watch::channel(false);
+        let connection = tokio::spawn(async move {
+            let (stream, source) = listener.accept().await.unwrap();
+            handler
+                .handle_tcp_connection(stream, source, shutdown)
+                .await
+        });
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let buf = many_questions(1);
+        stream
+            .write_all(&(buf.len() as u16).to_be_bytes())
+            .await
+            .unwrap();
+        stream.write_all(&buf).await.unwrap();
+        let mut len = [0u8; 2];
+        stream.read_exact(&mut len).await.unwrap();
+
+        // The connection is idle now, but it doesn't wait for the idle timeout.
+        shutdown_tx.send(true).unwrap();
+        let closed = tokio::time::timeout(Duration::from_secs(1), connection).await;
+        assert!(closed.unwrap().unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn dual_stack_listener() {
+        let socket = bind_udp("[::]:0".parse().unwrap(), false).unwrap();
+        let port = socket.local_addr().unwrap().port();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"query", ("127.0.0.1", port)).await.unwrap();
+        let mut buf = [0u8; 5];
+        let (_received, source) = socket.recv_from(&mut buf).await.unwrap();
+        assert_eq!(
+            Some(Ipv4Addr::LOCALHOST),
+            match source.ip() {
+                IpAddr::V6(ip) => ip.to_ipv4_mapped(),
+                IpAddr::V4(ip) => Some(ip),
+            }
+        );
+
+        let socket = bind_udp("[::]:0".parse().unwrap(), true).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        assert!(bind_udp(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)), false).is_ok());
+    }
+
+    /// A resolver on `address` that answers every query with "A 1.2.3.4", and counts the queries
+    async fn fake_resolver(address: &str) -> (SocketAddr, Arc<AtomicUsize>) {
+        let upstream = UdpSocket::bind(address).await.unwrap();
+        let resolver = upstream.local_addr().unwrap();
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = queries.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let (received, source) = upstream.recv_from(&mut buf).await.unwrap();
+                counter.fetch_add(1, Ordering::Relaxed);
+                let (_rest, mut msg) = Message::from_bytes((&buf[..received], 0)).unwrap();
+                msg.header.qr = Qr::Response;
+                msg.header.aa = 1;
+                msg.header.ra = 1;
+                msg.header.ancount = 1;
+                // The query's OPT record goes back, as it would from a resolver with EDNS(0).
+                msg.answer.push(ResourceRecord::new(
+                    msg.question[0].qname.clone(),
+                    Type::A,
+                    Class::IN,
+                    60,
+                    RData::A(Ipv4Addr::new(1, 2, 3, 4)),
+                ));
+                upstream
+                    .send_to(&msg.to_bytes().unwrap(), source)
+                    .await
+                    .unwrap();
+            }
+        });
+        (resolver, queries)
+    }
+
+    #[tokio::test]
+    async fn forwards_to_ipv6_resolver() {
+        let (resolver, _queries) = fake_resolver("[::1]:0").await;
+
+        let mut config = Config::default();
+        config.upstreams.resolvers = vec![resolver];
+        let handler = Handler::new(&config).unwrap();
+        let r_buf = handler
+            .respond(&many_questions(1), Transport::Udp)
+            .await
+            .unwrap();
+        let (_rest, rmsg) = Message::from_bytes((&r_buf, 0)).unwrap();
+
+        assert_eq!(ResponseCode::NoError, rmsg.header.rcode);
+        assert_eq!(1, rmsg.header.ra);
+        assert_eq!(RData::A(Ipv4Addr::new(1, 2, 3, 4)), rmsg.answer[0].rdata);
+    }
+
+    #[tokio::test]
+    async fn truncated_forwarded_answers_are_asked_again_over_tcp() {
+        // Over UDP, the resolver only says that the answer doesn't fit.
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let resolver = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let (received, source) = upstream.recv_from(&mut buf).await.unwrap();
+                let (_rest, mut msg) = Message::from_bytes((&buf[..received], 0)).unwrap();
+                msg.header.qr = Qr::Response;
+                msg.header.tc = 1;
+                let r_buf = msg.to_bytes().unwrap();
+                upstream.send_to(&r_buf, source).await.unwrap();
+            }
+        });
+        let listener = TcpListener::bind(resolver).await.unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut len = [0u8; 2];
+                stream.read_exact(&mut len).await.unwrap();
+                let mut buf = vec![0u8; u16::from_be_bytes(len) as usize];
+                stream.read_exact(&mut buf).await.unwrap();
+                let (_rest, mut msg) = Message::from_bytes((&buf, 0)).unwrap();
+                msg.header.qr = Qr::Response;
+                msg.header.ra = 1;
+                msg.header.ancount = 1;
+                msg.answer.push(ResourceRecord::new(
+                    msg.question[0].qname.clone(),
+                    Type::A,
+                    Class::IN,
+                    60,
+                    RData::A(Ipv4Addr::new(1, 2, 3, 4)),
+                ));
+                let r_buf = msg.to_bytes().unwrap();
+                stream
+                    .write_all(&(r_buf.len() as u16).to_be_bytes())
+                    .await
+                    .unwrap();
+                stream.write_all(&r_buf).await.unwrap();
+            }
+        });
+
+        let mut config = Config::default();
+        config.upstreams.resolvers = vec![resolver];
+        let handler = Handler::new(&config).unwrap();
+        let r_buf = handler
+            .respond(&many_questions(1), Transport::Udp)
+            .await
+            .unwrap();
+        let (_rest, rmsg) = Message::from_bytes((&r_buf, 0)).unwrap();
+
+        assert_eq!(0, rmsg.header.tc);
+        assert_eq!(RData::A(Ipv4Addr::new(1, 2, 3, 4)), rmsg.answer[0].rdata);
+    }
+
+    #[tokio::test]
+    async fn extended_rcodes_of_forwarded_answers_are_kept() {
+        // The resolver answers with BADVERS, whose upper bits are in its OPT record.
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let resolver = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let (received, source) = upstream.recv_from(&mut buf).await.unwrap();
+                let (_rest, mut msg) = Message::from_bytes((&buf[..received], 0)).unwrap();
+                msg.header.qr = Qr::Response;
+                let mut edns = Edns::new(1232);
+                edns.set_rcode(&mut msg.header.rcode, Edns::BADVERS);
+                msg.additional = vec![edns.to_record()];
+                let r_buf = msg.to_bytes().unwrap();
+                upstream.send_to(&r_buf, source).await.unwrap();
+            }
+        });
+
+        let mut config = Config::default();
+        config.upstreams.resolvers = vec![resolver];
+        let handler = Handler::new(&config).unwrap();
+
+        // A query with OPT
+        let mut buf = many_questions(1);
+        buf[11] = 1;
+        buf.extend([0, 0, 41, 16, 0, 0, 0, 0, 0, 0, 0]);
+        let r_buf = handler.respond(&buf, Transport::Udp).await.unwrap();
+        let (_rest, rmsg) = Message::from_bytes((&r_buf, 0)).unwrap();
+        let redns = rmsg.edns().unwrap().unwrap();
+        assert_eq!(
+            Edns::BADVERS,
+            (redns.extended_rcode as u16) << 4 | u8::from(rmsg.header.rcode) as u16
+        );
+
+        // Without OPT, the client can only be told that we failed.
+        let r_buf = handler
+            .respond(&many_questions(1), Transport::Udp)
+            .await
+            .unwrap();
+        let (_rest, rmsg) = Message::from_bytes((&r_buf, 0)).unwrap();
+        assert_eq!(ResponseCode::ServerFailure, rmsg.header.rcode);
+        assert!(rmsg.additional.is_empty());
+    }
+
+    #[tokio::test]
+    async fn forwarded_answers_are_cached() {
+        let (resolver, queries) = fake_resolver("127.0.0.1:0").await;
+        let mut config = Config::default();
+        config.upstreams.resolvers = vec![resolver];
+        let handler = Handler::new(&config).unwrap();
+
+        for aa in [1, 0] {
+            let r_buf = handler
+                .respond(&many_questions(1), Transport::Udp)
+                .await
+                .unwrap();
+            let (_rest, rmsg) = Message::from_bytes((&r_buf, 0)).unwrap();
+            assert_eq!(RData::A(Ipv4Addr::new(1, 2, 3, 4)), rmsg.answer[0].rdata);
+            assert_eq!(aa, rmsg.header.aa);
+        }
+        assert_eq!(1, queries.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn conditional_forwarding() {
+        let (corp, _queries) = fake_resolver("127.0.0.1:0").await;
+
+        // In the resolver mode, only the questions in the domain are forwarded.
+        let config = Config {
+            forward: vec![
+                ForwardRule {
+                    domain: "example".parse().unwrap(),
+                    resolvers: vec!["127.0.0.1:9".parse().unwrap()],
+                },
+                ForwardRule {
+                    domain: "abcdefghij.example".parse().unwrap(),
+                    resolvers: vec![corp],
+                },
+            ],
+            ..Default::default()
+        };
+        let handler = Handler::new(&config).unwrap();
+
+        let r_buf = handler
+            .respond(&many_questions(1), Transport::Udp)
+            .await
+            .unwrap();
+        let (_rest, rmsg) = Message::from_bytes((&r_buf, 0)).unwrap();
+        assert_eq!(1, rmsg.header.ra);
+        assert_eq!(RData::A(Ipv4Addr::new(1, 2, 3, 4)), rmsg.answer[0].rdata);
+
+        // "www.example.org" isn't in "example", as names are matched label by label.
+        let mut buf = vec![0, 7, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        buf.extend(b"\x03www\x07example\x03org\x00\x00\x01\x00\x01");
+        let r_buf = handler.respond(&buf, Transport::Udp).await.unwrap();
+        let (_rest, rmsg) = Message::from_bytes((&r_buf, 0)).unwrap();
+        assert_eq!(0, rmsg.header.ra);
+        assert_eq!(
+            RData::A(Ipv4Addr::new(192, 168, 1, 1)),
+            rmsg.answer[0].rdata
+        );
+    }
+
+    /// A query with a single question for `qname` of type `qtype` in the Internet class
+    fn query(qname: &str, qtype: Qtype) -> Vec<u8> {
+        let mut buf = vec![0, 7, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        buf.extend(qname.parse::<Name>().unwrap().as_bytes());
+        buf.extend(u16::from(qtype).to_be_bytes());
+        buf.extend([0, 1]);
+        buf
+    }
+
+    /// The response of `handler` to a query for `qname` of type `qtype`, over UDP
+    async fn respond(handler: &Handler, qname: &str, qtype: Qtype) -> Message {
+        let r_buf = handler
+            .respond(&query(qname, qtype), Transport::Udp)
+            .await
+            .unwrap();
+        Message::from_bytes((&r_buf, 0)).unwrap().1
+    }
+
+    #[tokio::test]
+    async fn authoritative_answers() {
+        let (corp, _queries) = fake_resolver("127.0.0.1:0").await;
+        let dir = TempDir::new(
+            "conn",
+            &[(
+                "example.com.zone",
+                "$ORIGIN example.com.\n$TTL 3600\n\
+                 @ SOA ns hostmaster 1 7200 900 604800 300\n\
+                 www A 192.0.2.1\n\
+                 sub NS ns.sub\n\
+                 ns.sub A 192.0.2.53\n",
+            )],
+        );
+        let zone = ZoneConfig {
+            file: dir.join("example.com.zone"),
+            origin: None,
+        };
+        let config = Config {
+            zone: vec![zone.clone()],
+            forward: vec![ForwardRule {
+                domain: "corp.example.com".parse().unwrap(),
+                resolvers: vec![corp],
+            }],
+            ..Default::default()
+        };
+        let handler = Handler::new(&config).unwrap();
+        let duplicate = Handler::new(&Config {
+            zone: vec![zone.clone(), zone],
+            ..Default::default()
+        });
+        assert!(matches!(duplicate, Err(ConfigError::Invalid("zone", _))));
+
+        let rmsg = respond(&handler, "www.example.com", Qtype::A).await;
+        assert_eq!((1, 0), (rmsg.header.aa, rmsg.header.ra));
+        assert_eq!(ResponseCode::NoError, rmsg.header.rcode);
+        assert_eq!(RData::A(Ipv4Addr::new(192, 0, 2, 1)), rmsg.answer[0].rdata);
+
+        // NODATA and NXDOMAIN, with the SOA record
+        let rmsg = respond(&handler, "www.example.com", Qtype::MX).await;
+        assert_eq!(1, rmsg.header.aa);
+        assert_eq!(ResponseCode::NoError, rmsg.header.rcode);
+        assert!(rmsg.answer.is_empty());
+        assert_eq!(Type::SOA, rmsg.authority[0].type_);
+        let rmsg = respond(&handler, "nx.example.com", Qtype::A).await;
+        assert_eq!(1, rmsg.header.aa);
+        assert_eq!(ResponseCode::NameError, rmsg.header.rcode);
+        assert_eq!(Type::SOA, rmsg.authority[0].type_);
+
+        // A referral, with glue
+        let rmsg = respond(&handler, "www.sub.example.com", Qtype::A).await;
+        assert_eq!(0, rmsg.header.aa);
+        assert_eq!(ResponseCode::NoError, rmsg.header.rcode);
+        assert!(rmsg.answer.is_empty());
+        assert_eq!(Type::NS, rmsg.authority[0].type_);
+        assert_eq!(
+            RData::A(Ipv4Addr::new(192, 0, 2, 53)),
+            rmsg.additional[0].rdata
+        );
+
+        // A conditional forwarding rule below the origin wins.
+        let rmsg = respond(&handler, "www.corp.example.com", Qtype::A).await;
+        assert_eq!(RData::A(Ipv4Addr::new(1, 2, 3, 4)), rmsg.answer[0].rdata);
+
+        // Names outside of the zone are resolved as before.
+        let rmsg = respond(&handler, "example.org", Qtype::A).await;
+        assert_eq!(0, rmsg.header.aa);
+        assert_eq!(
+            RData::A(Ipv4Addr::new(192, 168, 1, 1)),
+            rmsg.answer[0].rdata
+        );
+    }
+
+    #[tokio::test]
+    async fn aliases_are_followed() {
+        let (resolver, queries) = fake_resolver("127.0.0.1:0").await;
+        let dir = TempDir::new(
+            "cname",
+            &[
+                (
+                    "example.com.zone",
+                    "$ORIGIN example.com.\n$TTL 3600\n\
+                     @ SOA ns hostmaster 1 7200 900 604800 300\n\
+                     www CNAME web\n\
+                     web CNAME host.example.net.\n\
+                     dangling CNAME nx\n\
+                     loop1 CNAME loop2\n\
+                     loop2 CNAME loop1\n\
+                     long0 CNAME long1\nlong1 CNAME long2\nlong2 CNAME long3\nlong3 CNAME long4\n\
+                     long4 CNAME long5\nlong5 CNAME long6\nlong6 CNAME long7\nlong7 CNAME long8\n\
+                     long8 CNAME long9\nlong9 A 192.0.2.9\n\
+                     cached CNAME abcdefghij.example.\n",
+                ),
+                (
+                    "example.net.zone",
+                    "$ORIGIN example.net.\n$TTL 3600\n\
+                     @ SOA ns hostmaster 1 7200 900 604800 300\n\
+                     host A 192.0.2.7\n",
+                ),
+            ],
+        );
+        let config = Config {
+            upstreams: UpstreamsConfig {
+                resolvers: vec![resolver],
+                ..Default::default()
+            },
+            zone: ["example.com.zone", "example.net.zone"]
+                .into_iter()
+                .map(|file| ZoneConfig {
+                    file: dir.join(file),
+                    origin: None,
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let handler = Handler::new(&config).unwrap();
+
+        let types = |rmsg: &Message| rmsg.answer.iter().map(|rr| rr.type_).collect::<Vec<_>>();
+
+        // Across zones
+        let rmsg = respond(&handler, "www.example.com", Qtype::A).await;
+        assert_eq!(1, rmsg.header.aa);
+        assert_eq!(vec![Type::CNAME, Type::CNAME, Type::A], types(&rmsg));
+        assert_eq!(RData::A(Ipv4Addr::new(192, 0, 2, 7)), rmsg.answer[2].rdata);
+        // Questions about the aliases themselves aren't followed.
+        let rmsg = respond(&handler, "www.example.com", Qtype::CNAME).await;
+        assert_eq!(vec![Type::CNAME], types(&rmsg));
+
+        // A chain that ends at a name that doesn't exist
+        let rmsg = respond(&handler, "dangling.example.com", Qtype::A).await;
+        assert_eq!(ResponseCode::NameError, rmsg.header.rcode);
+        assert_eq!(vec![Type::CNAME], types(&rmsg));
+        assert_eq!(Type::SOA, rmsg.authority[0].type_);
+
+        // Loops, and chains that are too long, are cut off.
+        let rmsg = respond(&handler, "loop1.example.com", Qtype::A).await;
+        assert_eq!(ResponseCode::NoError, rmsg.header.rcode);
+        assert_eq!(2, rmsg.answer.len());
+        let rmsg = respond(&handler, "long0.example.com", Qtype::A).await;
+        assert_eq!(vec![Type::CNAME; 9], types(&rmsg));
+        let rmsg = respond(&handler, "long1.example.com", Qtype::A).await;
+        assert_eq!([vec![Type::CNAME; 8], vec![Type::A]].concat(), types(&rmsg));
+
+        // Only what's in the cache is followed, and nothing is forwarded for it.
+        let rmsg = respond(&handler, "cached.example.com", Qtype::A).await;
+        assert_eq!(vec![Type::CNAME], types(&rmsg));
+        assert_eq!(0, queries.load(Ordering::Relaxed));
+        respond(&handler, "abcdefghij.example", Qtype::A).await;
+        let rmsg = respond(&handler, "cached.example.com", Qtype::A).await;
+        assert_eq!(vec![Type::CNAME, Type::A], types(&rmsg));
+        assert_eq!(1, queries.load(Ordering::Relaxed));
+    }
+
+    /// An authoritative name server on `socket` for the zone in the master file `file`,
+    /// which counts the queries
+    fn name_server(socket: UdpSocket, file: PathBuf) -> Arc<AtomicUsize> {
+        let config = Config {
+            zone: vec![ZoneConfig { file, origin: None }],
+            ..Default::default()
+        };
+        let handler = Handler::new(&config).unwrap();
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = queries.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let (received, source) = socket.recv_from(&mut buf).await.unwrap();
+                counter.fetch_add(1, Ordering::Relaxed);
+                handler
+                    .handle_request(&socket, &buf[..received], source)
+                    .await
+                    .unwrap();
+            }
+        });
+        queries
+    }
+
+    #[tokio::test]
+    async fn recursive_resolution() {
+        // Name servers on addresses of the loopback network, which all listen on the same port:
+        // the root, "com.", "net.", and "example.com.", whose name server is out of its bailiwick.
+        let root = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = root.local_addr().unwrap().port();
+        let dir = TempDir::new(
+            "recursive",
+            &[
+                (
+                    "root.zone",
+                    "$TTL 3600\n\
+                     . SOA ns.root. hostmaster.root. 1 7200 900 604800 300\n\
+                     . NS ns.root.\nns.root. A 127.0.0.1\n\
+                     com. NS ns.com.\nns.com. A 127.0.0.2\n\
+                     net. NS ns.net.\nns.net. A 127.0.0.4\n",
+                ),
+                (
+                    "com.zone",
+                    "$TTL 3600\n$ORIGIN com.\n@ SOA ns hostmaster 1 7200 900 604800 300\n\
+                     @ NS ns\nns A 127.0.0.2\n\
+                     example NS ns.example.net.\n",
+                ),
+                (
+                    "net.zone",
+                    "$TTL 3600\n$ORIGIN net.\n@ SOA ns hostmaster 1 7200 900 604800 300\n\
+                     @ NS ns\nns A 127.0.0.4\n\
+                     ns.example A 127.0.0.3\nwww.example A 192.0.2.8\n",
+                ),
+                (
+                    "example.com.zone",
+                    "$TTL 3600\n$ORIGIN example.com.\n\
+                     @ SOA ns.example.net. hostmaster 1 7200 900 604800 300\n\
+                     @ NS ns.example.net.\nwww A 192.0.2.1\n\
+                     alias CNAME www.example.net.\n",
+                ),
+                (
+                    "root.hints",
+                    ". 3600000 NS ns.root.\nns.root. 3600000 A 127.0.0.1\n",
+                ),
+            ],
+        );
+        let servers = [
+            (root, "root.zone"),
+            (
+                UdpSocket::bind(("127.0.0.2", port)).await.unwrap(),
+                "com.zone",
+            ),
+            (
+                UdpSocket::bind(("127.0.0.4", port)).await.unwrap(),
+                "net.zone",
+            ),
+            (
+                UdpSocket::bind(("127.0.0.3", port)).await.unwrap(),
+                "example.com.zone",
+            ),
+        ];
+        let queries: Vec<Arc<AtomicUsize>> = servers
+            .into_iter()
+            .map(|(socket, file)| name_server(socket, dir.join(file)))
+            .collect();
+        let config = Config {
+            resolve: ResolveConfig {
+                root_hints: Some(dir.join("root.hints")),
+                port,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let handler = Handler::new(&config).unwrap();
+
+        let counts = || {
+            queries
+                .iter()
+                .map(|q| q.load(Ordering::Relaxed))
+                .collect::<Vec<_>>()
+        };
+
+        // From the root down, resolving the name server of "example.com." on the way
+        let rmsg = respond(&handler, "www.example.com", Qtype::A).await;
+        assert_eq!((0, 1), (rmsg.header.aa, rmsg.header.ra));
+        assert_eq!(ResponseCode::NoError, rmsg.header.rcode);
+        assert_eq!(RData::A(Ipv4Addr::new(192, 0, 2, 1)), rmsg.answer[0].rdata);
+        assert_eq!(vec![2, 1, 1, 1], counts());
+
+        // The delegations, and the answers, are cached.
+        let rmsg = respond(&handler, "nx.example.com", Qtype::A).await;
+        assert_eq!(ResponseCode::NameError, rmsg.header.rcode);
+        assert_eq!(Type::SOA, rmsg.authority[0].type_);
+        assert_eq!(vec![2, 1, 1, 2], counts());
+        respond(&handler, "www.example.com", Qtype::A).await;
+        respond(&handler, "nx.example.com", Qtype::A).await;
+        assert_eq!(vec![2, 1, 1, 2], counts());
+
+        // Aliases to other zones are resolved as well.
+        let rmsg = respond(&handler, "alias.example.com", Qtype::A).await;
+        let types: Vec<Type> = rmsg.answer.iter().map(|rr| rr.type_).collect();
+        assert_eq!(vec![Type::CNAME, Type::A], types);
+        assert_eq!(RData::A(Ipv4Addr::new(192, 0, 2, 8)), rmsg.answer[1].rdata);
+        assert_eq!(vec![2, 1, 2, 3], counts());
+
+        let stats = handler.recursor.as_ref().unwrap().stats();
+        assert_eq!(4, stats.len());
+        assert!(stats
+            .iter()
+            .all(|s| s.srtt.is_some() && s.failures == 0 && s.address.port() == port));
+    }
+
+    #[tokio::test]
+    async fn truncated_answers_of_name_servers_are_asked_again_over_tcp() {
+        // A root server that answers everything itself, but over UDP only says that it doesn't fit
+        let dir = TempDir::new(
+            "truncated",
+            &[
+                (
+                    "root.zone",
+                    "$TTL 3600\n\
+                     . SOA ns.root. hostmaster.root. 1 7200 900 604800 300\n\
+                     . NS ns.root.\nns.root. A 127.0.0.1\n\
+                     www.example. A 192.0.2.1\n",
+                ),
+                (
+                    "root.hints",
+                    ". 3600000 NS ns.root.\nns.root. 3600000 A 127.0.0.1\n",
+                ),
+            ],
+        );
+        let root = Handler::new(&Config {
+            zone: vec![ZoneConfig {
+                file: dir.join("root.zone"),
+                origin: None,
+            }],
+            ..Default::default()
+        })
+        .unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = socket.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let (received, source) = socket.recv_from(&mut buf).await.unwrap();
+                let (_rest, mut msg) = Message::from_bytes((&buf[..received], 0)).unwrap();
+                msg.header.qr = Qr::Response;
+                msg.header.tc = 1;
+                let r_buf = msg.to_bytes().unwrap();
+                socket.send_to(&r_buf, source).await.unwrap();
+            }
+        });
+        let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut len = [0u8; 2];
+                stream.read_exact(&mut len).await.unwrap();
+                let mut buf = vec![0u8; u16::from_be_bytes(len) as usize];
+                stream.read_exact(&mut buf).await.unwrap();
+                let r_buf = root.respond(&buf, Transport::Tcp).await.unwrap();
+                stream
+                    .write_all(&(r_buf.len() as u16).to_be_bytes())
+                    .await
+                    .unwrap();
+                stream.write_all(&r_buf).await.unwrap();
+            }
+        });
+
+        let config = Config {
+            resolve: ResolveConfig {
+                root_hints: Some(dir.join("root.hints")),
+                port,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let handler = Handler::new(&config).unwrap();
+
+        let rmsg = respond(&handler, "www.example", Qtype::A).await;
+        assert_eq!(0, rmsg.header.tc);
+        assert_eq!(ResponseCode::NoError, rmsg.header.rcode);
+        assert_eq!(RData::A(Ipv4Addr::new(192, 0, 2, 1)), rmsg.answer[0].rdata);
     }
 }
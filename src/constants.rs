@@ -5,9 +5,85 @@
 /// Local host IPv4 address and port
 pub const LOCAL_SOCKET_ADDR_STR: &str = "127.0.0.1:2053";
 
-/// Length of buffer for handling connections, 512 bytes
+/// Maximum length of a UDP message without EDNS(0), 512 bytes
 pub const BUFFER_LEN: usize = 1 << 9;
 
+/// Default maximum UDP payload size with EDNS(0), which avoids IP fragmentation on most paths
+pub const DEFAULT_MAX_UDP_PAYLOAD: u16 = 1232;
+
+/// Maximum number of requests that are handled concurrently
+pub const MAX_CONCURRENT_REQUESTS: usize = 1024;
+
+/// Maximum number of TCP connections that are handled concurrently
+pub const MAX_TCP_CONNECTIONS: usize = 128;
+
+/// Maximum number of queries on a single TCP connection that are handled concurrently
+pub const MAX_TCP_PIPELINED_QUERIES: usize = 16;
+
+/// Time after which a TCP connection on which no query arrives is closed, in milliseconds
+pub const TCP_IDLE_TIMEOUT_MS: u64 = 10_000;
+
+/// Delay after the first of consecutive transient errors while receiving, in milliseconds;
+/// the delay doubles with every following one, up to [`MAX_RECV_BACKOFF_MS`]
+pub const MIN_RECV_BACKOFF_MS: u64 = 1;
+
+/// Maximum delay after a transient error while receiving, in milliseconds
+pub const MAX_RECV_BACKOFF_MS: u64 = 1000;
+
+/// Default time to wait for an answer from an upstream resolver to a single attempt, in milliseconds
+pub const UPSTREAM_TIMEOUT_MS: u64 = 2_000;
+
+/// Default number of attempts at every upstream resolver, before failing over to the next one
+pub const UPSTREAM_ATTEMPTS: u32 = 2;
+
+/// Default number of consecutive failed queries after which an upstream resolver is marked down
+pub const UPSTREAM_MAX_FAILURES: u32 = 3;
+
+/// Default time after which an upstream resolver that is down is probed again, in milliseconds
+pub const UPSTREAM_PROBE_INTERVAL_MS: u64 = 30_000;
+
+/// Default number of upstream resolvers that are queried at the same time, when they race
+pub const UPSTREAM_RACE_COUNT: usize = 2;
+
+/// Default maximum number of answers in the cache
+pub const CACHE_MAX_ENTRIES: usize = 10_000;
+
+/// Default least time to keep an answer in the cache for, in seconds
+pub const CACHE_MIN_TTL: u32 = 0;
+
+/// Default most time to keep an answer in the cache for, in seconds
+pub const CACHE_MAX_TTL: u32 = 86_400;
+
+/// Time that the queries in flight get to be answered when shutting down, in milliseconds
+pub const SHUTDOWN_DEADLINE_MS: u64 = 5_000;
+
+/// Number of aliases that are followed for a question
+pub const MAX_CNAME_CHAIN: usize = 8;
+
+/// Default port that name servers are queried on, when we resolve queries recursively
+pub const NAME_SERVER_PORT: u16 = 53;
+
+/// Default time to wait for an answer from a name server, when we resolve queries recursively,
+/// in milliseconds
+pub const NAME_SERVER_TIMEOUT_MS: u64 = 1_500;
+
+/// Default time that resolving a single question recursively may take, however many name servers
+/// it asks, and however many aliases in its answer are followed, in milliseconds
+pub const RESOLUTION_DEADLINE_MS: u64 = 10_000;
+
+/// Number of name servers whose statistics are kept, when we resolve queries recursively
+pub const MAX_NAME_SERVER_STATS: usize = 1_000;
+
+/// Number of referrals that are followed while resolving a name, from the closest known zone down
+pub const MAX_REFERRALS: usize = 16;
+
+/// How deeply the addresses of name servers are resolved while resolving another name,
+/// which stops delegations that depend on each other from going around in circles
+pub const MAX_RECURSION_DEPTH: usize = 4;
+
+/// How deeply `$INCLUDE` directives in zone files can nest, which stops inclusion loops
+pub const MAX_ZONE_INCLUDE_DEPTH: usize = 8;
+
 /// Time-to-live
 pub const TTL: u32 = 60;
 
@@ -15,9 +91,20 @@ pub const TTL: u32 = 60;
 pub const ARBITRARY_IPV4: [u8; 4] = [192, 168, 1, 1];
 
 /// Application exit codes
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ExitCode {
+    /// Shut down on SIGINT or SIGTERM, after answering all the queries in flight
+    Success = 0,
+
+    /// Unable to listen for the shutdown signals
     Shutdown = -1,
+
+    /// Receiving on a UDP socket failed with an error that isn't transient
     UdpRecv = -2,
-    ForwardingError = -3,
+
+    /// The configuration is invalid, binding to a listen address failed, or a listener failed
+    Startup = -4,
+
+    /// Shut down on SIGINT or SIGTERM, with queries still in flight after [`SHUTDOWN_DEADLINE_MS`]
+    ShutdownDeadline = -5,
 }
@@ -3,6 +3,7 @@
 //! Error types and helper functions used in the library
 
 use std::array::TryFromSliceError;
+use std::io::ErrorKind;
 
 use deku::DekuError;
 use thiserror::Error;
@@ -10,13 +11,61 @@ use thiserror::Error;
 /// Application errors
 #[derive(Debug, Error)]
 pub enum ApplicationError {
+    #[error(transparent)]
+    ConfigError(#[from] ConfigError),
+
     #[error(transparent)]
     ConnectionError(#[from] ConnectionError),
 
+    /// Receiving on a UDP socket failed with an error that isn't transient
+    #[error(transparent)]
+    UdpRecv(ConnectionError),
+
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
 
+/// Errors related to working with [`crate::config`]
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Failed to read the configuration file {0}: {1}")]
+    Read(String, std::io::Error),
+
+    #[error("Failed to parse the configuration file {0}: {1}")]
+    Parse(String, toml::de::Error),
+
+    #[error("Invalid mode \"{0}\"; expected \"resolve\" or \"forward\"")]
+    InvalidMode(String),
+
+    #[error("The forwarding mode needs at least one resolver")]
+    NoResolvers,
+
+    #[error("Resolvers are only used in the forwarding mode")]
+    UnusedResolvers,
+
+    #[error("Invalid log level \"{0}\"; expected off, error, warn, info, debug or trace")]
+    InvalidLogLevel(String),
+
+    #[error("Invalid `{0}`: {1}")]
+    Invalid(&'static str, String),
+
+    #[error(transparent)]
+    ZoneError(#[from] ZoneError),
+}
+
+/// Errors related to working with [`crate::zone`] and [`crate::zonefile`]
+#[derive(Debug, Error)]
+pub enum ZoneError {
+    #[error("Failed to read the zone file {0}: {1}")]
+    Read(String, std::io::Error),
+
+    #[error("Syntax error in the zone file {0}, line {1}: {2}")]
+    Syntax(String, usize, String),
+
+    #[error("Invalid zone {0}: {1}")]
+    Invalid(String, String),
+}
+
 /// Errors related to working with [`crate::conn`]
 #[derive(Debug, Error)]
 pub enum ConnectionError {
@@ -26,8 +75,11 @@ pub enum ConnectionError {
     #[error("Failed to send response to {0}")]
     SendError(std::io::Error),
 
-    #[error("received '\0' where we shoudn't have")]
-    ZeroByte,
+    #[error("Message {0} is not a query")]
+    NotAQuery(u16),
+
+    #[error("Error communicating with the upstream resolver: {0}")]
+    UpstreamError(std::io::Error),
 
     #[error(transparent)]
     DekuError(#[from] DekuError),
@@ -36,25 +88,101 @@ pub enum ConnectionError {
     Slice(#[from] TryFromSliceError),
 
     #[error(transparent)]
-    QtypeError(#[from] QtypeError),
+    NameError(#[from] NameError),
 
     #[error(transparent)]
-    QclassError(#[from] QclassError),
+    EdnsError(#[from] EdnsError),
 
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
 
-/// Errors related to working with [`crate::message::Qtype`]
+impl ConnectionError {
+    /// Is this a transient error, after which the socket can still be used
+    ///
+    /// On Linux, a UDP socket reports an ICMP error, such as port unreachable, that was caused by
+    /// an earlier datagram on a later call, and calls can be interrupted or run short of memory.
+    /// None of these mean that the socket itself is broken, unlike, for example, a bad descriptor.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::RecvError(e) | Self::SendError(e) | Self::UpstreamError(e) => matches!(
+                e.kind(),
+                ErrorKind::ConnectionRefused
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::Interrupted
+                    | ErrorKind::WouldBlock
+                    | ErrorKind::TimedOut
+                    | ErrorKind::OutOfMemory
+            ),
+            _ => false,
+        }
+    }
+}
+
+/// Errors related to working with [`crate::message::Name`]
 #[derive(Debug, Error)]
-pub enum QtypeError {
-    #[error("Unsupported Qtype: {0}")]
-    UnsupportedQtype(u16),
+pub enum NameError {
+    #[error("Label is {0} bytes long, but at most 63 are allowed")]
+    LabelTooLong(usize),
+
+    #[error("Name is {0} bytes long, but at most 255 are allowed")]
+    NameTooLong(usize),
+
+    #[error("Empty label in name \"{0}\"")]
+    EmptyLabel(String),
+
+    #[error("Invalid escape sequence in name \"{0}\"")]
+    InvalidEscape(String),
+
+    #[error("Unsupported label type {0:#04x}")]
+    InvalidLabelType(u8),
+
+    #[error("Compression pointer to {0} points forward")]
+    ForwardPointer(u16),
+
+    #[error("Compression pointer to {0} forms a loop")]
+    PointerLoop(u16),
+
+    #[error("Name is truncated")]
+    Truncated,
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
 }
 
-/// Errors related to working with [`crate::message::Qclass`]
+/// Errors related to working with [`crate::message::Edns`]
 #[derive(Debug, Error)]
-pub enum QclassError {
-    #[error("Unsupported Qclass: {0}")]
-    UnsupportedQclass(u16),
+pub enum EdnsError {
+    #[error("Message has more than one OPT record")]
+    MultipleOpt,
+
+    #[error("OPT record is owned by \"{0}\" instead of the root")]
+    NotRoot(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::ConnectionError;
+    use std::io::{Error, ErrorKind};
+
+    #[test]
+    fn transient_errors() {
+        for kind in [
+            ErrorKind::ConnectionRefused,
+            ErrorKind::ConnectionReset,
+            ErrorKind::Interrupted,
+            ErrorKind::WouldBlock,
+        ] {
+            assert!(ConnectionError::RecvError(Error::from(kind)).is_transient());
+        }
+
+        for kind in [
+            ErrorKind::InvalidInput,
+            ErrorKind::PermissionDenied,
+            ErrorKind::Other,
+        ] {
+            assert!(!ConnectionError::RecvError(Error::from(kind)).is_transient());
+        }
+    }
 }
@@ -0,0 +1,48 @@
+//! # Test Fixtures
+//!
+//! Helpers that the tests of several modules share
+
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// A temporary directory for a test, which is removed with everything in it when it's dropped,
+/// even if the test fails
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// A new temporary directory for the test `name`, with the `files`, by their names and contents
+    pub fn new(name: &str, files: &[(&str, &str)]) -> Self {
+        let dir = std::env::temp_dir().join(format!("dns-server-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir = Self(dir);
+        for (file, contents) in files {
+            dir.write(file, contents);
+        }
+        dir
+    }
+
+    /// Write `contents` to `file` in the directory, creating the directories on the way,
+    /// and return its path
+    pub fn write(&self, file: &str, contents: &str) -> PathBuf {
+        let path = self.0.join(file);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).unwrap();
+        }
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
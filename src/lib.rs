@@ -1,6 +1,15 @@
 //! # A DNS Server Library
 
+pub mod cache;
+pub mod config;
 pub mod conn;
 pub mod constants;
 pub mod errors;
+#[cfg(test)]
+mod fixtures;
 pub mod message;
+pub mod recursor;
+pub mod selection;
+pub mod upstream;
+pub mod zone;
+pub mod zonefile;
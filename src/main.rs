@@ -1,74 +1,467 @@
 //! # A DNS Server Application
 
 use anyhow::{Context, Result};
-use dns_server::conn::handle_request;
-use dns_server::constants::{ExitCode, LOCAL_SOCKET_ADDR_STR};
-use dns_server::errors::{ApplicationError, ConnectionError};
-use log::{error, info, warn};
-use std::env;
-use std::net::SocketAddrV4;
+use clap::Parser;
+use dns_server::config::{Config, ForwardRule, Mode, ZoneConfig};
+use dns_server::conn::{bind_tcp, bind_udp, Handler};
+use dns_server::constants::{
+    ExitCode, MAX_CONCURRENT_REQUESTS, MAX_RECV_BACKOFF_MS, MAX_TCP_CONNECTIONS,
+    MIN_RECV_BACKOFF_MS, SHUTDOWN_DEADLINE_MS,
+};
+use dns_server::errors::{ApplicationError, ConfigError, ConnectionError, NameError};
+use dns_server::message::Name;
+use log::{error, info, warn, LevelFilter};
+use std::net::{AddrParseError, SocketAddr};
+use std::path::PathBuf;
 use std::process::exit;
-use tokio::net::UdpSocket;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
+use tokio::net::{TcpListener, UdpSocket};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+
+/// A DNS server that resolves queries itself, or forwards them to upstream resolvers
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    /// Address to listen on over UDP and TCP, such as 127.0.0.1:2053 or [::1]:2053;
+    /// can be repeated [default: 127.0.0.1:2053]
+    #[arg(long, value_name = "ADDRESS")]
+    listen: Vec<SocketAddr>,
+
+    /// Upstream resolver to forward queries to, such as 8.8.8.8:53 or [2001:4860:4860::8888]:53;
+    /// can be repeated, in order of preference
+    #[arg(long, value_name = "ADDRESS")]
+    resolver: Vec<SocketAddr>,
+
+    /// Conditional forwarding rule, such as corp.example=10.0.0.53:53: questions about names in the domain
+    /// are forwarded to the resolver, in any mode; can be repeated
+    #[arg(long, value_name = "DOMAIN=ADDRESS", value_parser = parse_forward_rule)]
+    forward: Vec<(Name, SocketAddr)>,
+
+    /// Zone file in the master file format, whose zone is answered authoritatively, in any mode;
+    /// the origin of the zone is the owner of its SOA record; can be repeated
+    #[arg(long, value_name = "FILE")]
+    zone: Vec<PathBuf>,
+
+    /// Root hints: a master file with the NS records of the root zone and the addresses of the root servers;
+    /// with them, queries are resolved recursively in the resolver mode, starting from the root servers
+    #[arg(long, value_name = "FILE")]
+    root_hints: Option<PathBuf>,
+
+    /// How queries are answered: "resolve" them ourselves, or "forward" them to the resolvers
+    /// [default: "forward" if there are resolvers, and "resolve" otherwise]
+    #[arg(long)]
+    mode: Option<Mode>,
+
+    /// Configuration file in TOML, which is read again on SIGHUP;
+    /// options on the command line take precedence over it
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// The most detailed level of messages to log: off, error, warn, info, debug or trace
+    /// [default: the RUST_LOG environment variable]
+    #[arg(long, value_name = "LEVEL")]
+    log_level: Option<LevelFilter>,
+}
+
+/// Parse a conditional forwarding rule of the form `DOMAIN=ADDRESS`
+fn parse_forward_rule(rule: &str) -> Result<(Name, SocketAddr), String> {
+    let (domain, resolver) = rule
+        .split_once('=')
+        .ok_or_else(|| "expected DOMAIN=ADDRESS".to_string())?;
+    let domain = domain.parse().map_err(|e: NameError| e.to_string())?;
+    let resolver = resolver
+        .parse()
+        .map_err(|e: AddrParseError| e.to_string())?;
+    Ok((domain, resolver))
+}
 
 #[tokio::main]
-async fn main() -> Result<(), ApplicationError> {
-    env_logger::init();
+async fn main() {
+    let args = Args::parse();
+
+    match run(args).await {
+        Ok(exit_code) => exit(exit_code as i32),
+        Err(e) => {
+            eprintln!("Error: {e:#}");
+            exit(ExitCode::Startup as i32)
+        }
+    }
+}
+
+/// The handler of queries, which is replaced when the configuration is reloaded
+///
+/// Every query takes the current handler when it arrives, and keeps it until it's answered,
+/// so queries in flight during a reload are answered according to the old configuration.
+type SharedHandler = Arc<RwLock<Arc<Handler>>>;
+
+impl Args {
+    /// The configuration file's settings, if there is one, with the options replacing them
+    fn config(&self) -> Result<Config, ConfigError> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        if !self.listen.is_empty() {
+            config.server.listen = self.listen.clone();
+        }
+        if self.mode.is_some() {
+            config.server.mode = self.mode;
+        }
+        if !self.resolver.is_empty() {
+            config.upstreams.resolvers = self.resolver.clone();
+        }
+        if !self.forward.is_empty() {
+            config.forward = vec![];
+            for (domain, resolver) in &self.forward {
+                match config
+                    .forward
+                    .iter_mut()
+                    .find(|rule| rule.domain == *domain)
+                {
+                    Some(rule) => rule.resolvers.push(*resolver),
+                    None => config.forward.push(ForwardRule {
+                        domain: domain.clone(),
+                        resolvers: vec![*resolver],
+                    }),
+                }
+            }
+        }
+        if !self.zone.is_empty() {
+            config.zone = self
+                .zone
+                .iter()
+                .map(|file| ZoneConfig {
+                    file: file.clone(),
+                    origin: None,
+                })
+                .collect();
+        }
+        if self.root_hints.is_some() {
+            config.resolve.root_hints = self.root_hints.clone();
+        }
+        if self.log_level.is_some() {
+            config.log.level = self.log_level;
+        }
+        config.validate()?;
+
+        Ok(config)
+    }
+}
+
+/// Apply the configuration, bind to the listen addresses, and serve queries on them until shutdown
+///
+/// On SIGINT or SIGTERM, we stop accepting queries and connections, and give the queries that
+/// are in flight [`SHUTDOWN_DEADLINE_MS`] to be answered; the exit code tells whether they all were.
+/// If a listener fails, we shut down the same way, with an exit code that tells why.
+async fn run(args: Args) -> Result<ExitCode, ApplicationError> {
+    let config = args.config()?;
+
+    // With a log level in the configuration, every message reaches the global maximum level,
+    // which a reload can change.
+    let mut logger = env_logger::Builder::from_default_env();
+    if config.log.level.is_some() {
+        logger.filter_level(LevelFilter::Trace);
+    }
+    logger.init();
+    if let Some(level) = config.log.level {
+        log::set_max_level(level);
+    }
     info!("Starting the DNS server...");
 
-    let args = env::args().collect::<Vec<String>>();
-    let mut resolver: Option<SocketAddrV4> = None;
-    if args.len() >= 3 && args[1] == "--resolver" {
-        info!("Working in the forwarding mode; forward to {}", args[2]);
-        resolver = Some(args[2].parse().expect("Failed to parse resolver address"));
-    } else {
-        info!("Working in the resolver mode.");
+    log_mode(&config)?;
+    let handler: SharedHandler = Arc::new(RwLock::new(Arc::new(Handler::new(&config)?)));
+
+    let listen = config.server.listen;
+    let permits = Arc::new(Semaphore::new(MAX_CONCURRENT_REQUESTS));
+    let tcp_permits = Arc::new(Semaphore::new(MAX_TCP_CONNECTIONS));
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let mut listeners = JoinSet::new();
+    for &address in &listen {
+        let only_v6 = only_v6(address, &listen);
+        let udp_socket = bind_udp(address, only_v6)
+            .with_context(|| format!("Failed to bind to address {}", address))?;
+        let tcp_listener = bind_tcp(address, only_v6)
+            .with_context(|| format!("Failed to bind to address {}", address))?;
+        info!("Listening on {}", address);
+
+        listeners.spawn(tcp_loop(
+            tcp_listener,
+            handler.clone(),
+            tcp_permits.clone(),
+            shutdown_rx.clone(),
+        ));
+        listeners.spawn(main_loop(udp_socket, handler.clone(), permits.clone()));
+    }
+
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup(args, handler, listen));
+
+    let signal = shutdown_signal();
+    tokio::pin!(signal);
+    let mut exit_code = loop {
+        tokio::select! {
+            signal = &mut signal => break match signal {
+                Ok(signal) => {
+                    info!("{signal} received. Shutting down...");
+                    ExitCode::Success
+                }
+                Err(e) => {
+                    // We also shut down in case of error.
+                    error!("Unable to listen for the shutdown signals: {e}");
+                    ExitCode::Shutdown
+                }
+            },
+            Some(res) = listeners.join_next() => {
+                // A listener only returns if it fails, and then the others shut down as well.
+                let e = match res.context("A listener has panicked") {
+                    Ok(Ok(())) => continue,
+                    Ok(Err(e)) => e,
+                    Err(e) => e.into(),
+                };
+                error!("{e:#}");
+                break match e {
+                    ApplicationError::UdpRecv(_) => ExitCode::UdpRecv,
+                    _ => ExitCode::Startup,
+                };
+            }
+        }
+    };
+
+    // Stop accepting, and close the TCP connections once they're done with their queries.
+    listeners.shutdown().await;
+    let _ = shutdown_tx.send(true);
+
+    // A query or a connection holds its permit until it's done.
+    let drained = timeout(Duration::from_millis(SHUTDOWN_DEADLINE_MS), async {
+        let _ = permits.acquire_many(MAX_CONCURRENT_REQUESTS as u32).await;
+        let _ = tcp_permits.acquire_many(MAX_TCP_CONNECTIONS as u32).await;
+    })
+    .await;
+    match drained {
+        Ok(()) => info!("All the queries in flight have been answered."),
+        Err(_) => {
+            warn!("Queries were still in flight after {SHUTDOWN_DEADLINE_MS} ms");
+            if exit_code == ExitCode::Success {
+                exit_code = ExitCode::ShutdownDeadline;
+            }
+        }
     }
 
-    let udp_socket = UdpSocket::bind(LOCAL_SOCKET_ADDR_STR)
-        .await
-        .with_context(|| format!("Failed to bind to address {}", LOCAL_SOCKET_ADDR_STR))?;
+    info!("Terminating the app ({})...", exit_code as i32);
+    log::logger().flush();
 
-    main_loop(udp_socket, resolver).await
+    Ok(exit_code)
+}
+
+/// Wait for SIGINT or SIGTERM, and return the name of the signal
+async fn shutdown_signal() -> std::io::Result<&'static str> {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => res.map(|()| "SIGINT"),
+            _ = terminate.recv() => Ok("SIGTERM"),
+        }
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.map(|()| "CTRL+C")
+}
+
+/// Log the mode that `config` works in, and the conditional forwarding rules
+fn log_mode(config: &Config) -> Result<(), ConfigError> {
+    match config.mode()? {
+        Mode::Resolve => match &config.resolve.root_hints {
+            Some(root_hints) => info!(
+                "Working in the resolver mode; resolve recursively from the root hints in {}",
+                root_hints.display()
+            ),
+            None => info!("Working in the resolver mode."),
+        },
+        Mode::Forward => info!(
+            "Working in the forwarding mode; forward to {:?}",
+            config.upstreams.resolvers
+        ),
+    }
+    for rule in &config.forward {
+        info!("Forwarding {} to {:?}", rule.domain, rule.resolvers);
+    }
+
+    Ok(())
+}
+
+/// The handler that is current at the moment
+fn current(handler: &SharedHandler) -> Arc<Handler> {
+    handler
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+/// Reload the configuration on every SIGHUP
+///
+/// The configuration file is read and validated again, and the options on the command line
+/// still take precedence over it. If it's valid, it replaces the current handler, and takes over
+/// what that has learned; otherwise, the error is logged, and the current configuration stays in effect.
+///
+/// The listen addresses can't change without a restart.
+#[cfg(unix)]
+async fn reload_on_hangup(args: Args, handler: SharedHandler, listen: Vec<SocketAddr>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            warn!("Unable to listen for SIGHUP, so the configuration can't be reloaded: {e}");
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        info!("SIGHUP received. Reloading the configuration...");
+        let reloaded = args.config().and_then(|config| {
+            let new_handler = Handler::new(&config)?;
+            log_mode(&config)?;
+            Ok((config, new_handler))
+        });
+        let (config, new_handler) = match reloaded {
+            Ok(res) => res,
+            Err(e) => {
+                error!("{e}");
+                error!("Keeping the current configuration.");
+                continue;
+            }
+        };
+
+        if config.server.listen != listen {
+            warn!("The listen addresses can only change with a restart; still listening on {listen:?}");
+        }
+        if let Some(level) = config.log.level {
+            log::set_max_level(level);
+        }
+        new_handler.inherit(&current(&handler));
+        *handler.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(new_handler);
+        info!("The configuration has been reloaded.");
+    }
+}
+
+/// Should the IPv6 `address` be restricted to IPv6, rather than accept IPv4 traffic as well
+///
+/// `[::]` is dual-stack, unless an IPv4 address among all the `listen` addresses has the same port,
+/// as the two would conflict.
+fn only_v6(address: SocketAddr, listen: &[SocketAddr]) -> bool {
+    !address.ip().is_unspecified()
+        || listen
+            .iter()
+            .any(|other| other.is_ipv4() && other.port() == address.port())
 }
 
 /// Resolve DNS queries
+///
+/// Datagrams are received continuously, and every query is handled in its own task,
+/// so a slow query doesn't hold up the others.
+/// At most [`MAX_CONCURRENT_REQUESTS`] queries are handled at the same time, over all listen addresses,
+/// as they share the `permits`.
+///
+/// Transient errors while receiving are logged and counted, and receiving is retried after
+/// a delay that grows with every consecutive error; any other error is returned, and shuts the app down.
 async fn main_loop(
     udp_socket: UdpSocket,
-    resolver: Option<SocketAddrV4>,
+    handler: SharedHandler,
+    permits: Arc<Semaphore>,
 ) -> Result<(), ApplicationError> {
     info!("Waiting for requests...");
 
+    let udp_socket = Arc::new(udp_socket);
+    let mut transient_errors: u64 = 0;
+    let mut backoff_ms = MIN_RECV_BACKOFF_MS;
+
     loop {
-        match handle_request(&udp_socket, resolver).await {
-            Ok(_) => {}
-            Err(ConnectionError::RecvError(e)) => {
-                error!("{e}");
-                error!("Terminating the app ({})...", ExitCode::UdpRecv as i32);
-                exit(ExitCode::UdpRecv as i32)
+        let permit = permits
+            .clone()
+            .acquire_owned()
+            .await
+            .context("The request semaphore was closed")?;
+
+        let mut buf = vec![0u8; current(&handler).max_udp_payload() as usize];
+        let (received, source) = match udp_socket.recv_from(&mut buf).await {
+            Ok(res) => {
+                backoff_ms = MIN_RECV_BACKOFF_MS;
+                res
             }
             Err(e) => {
-                warn!("{e}");
+                let e = ConnectionError::RecvError(e);
+                if !e.is_transient() {
+                    return Err(ApplicationError::UdpRecv(e));
+                }
+                transient_errors += 1;
+                warn!("{e} (transient error #{transient_errors}; retrying in {backoff_ms} ms)");
+                tokio::time::sleep(Duration::from_millis(backoff_ms)).await;
+                backoff_ms = (backoff_ms * 2).min(MAX_RECV_BACKOFF_MS);
+                continue;
             }
-        }
+        };
+        info!("<= Received {} bytes from {}", received, source);
 
-        shutdown().await;
+        let udp_socket = udp_socket.clone();
+        let handler = current(&handler);
+        tokio::spawn(async move {
+            if let Err(e) = handler
+                .handle_request(&udp_socket, &buf[..received], source)
+                .await
+            {
+                warn!("{e}");
+            }
+            drop(permit);
+        });
     }
 }
 
-/// Await the shutdown signal
-async fn shutdown() {
-    tokio::spawn(async move {
-        match tokio::signal::ctrl_c().await {
-            Ok(()) => {
-                info!("CTRL+C received. Shutting down...");
-                exit(0);
-            }
-            Err(err) => {
-                // We also shut down in case of error.
-                error!("Unable to listen for the shutdown signal: {}", err);
-                error!("Terminating the app ({})...", ExitCode::Shutdown as i32);
-                exit(ExitCode::Shutdown as i32)
+/// Accept TCP connections
+///
+/// Every connection is handled in its own task,
+/// according to the configuration that is current when the connection is accepted.
+/// At most [`MAX_TCP_CONNECTIONS`] connections are handled at the same time, over all listen
+/// addresses, as they share the `permits`; further ones wait in the listen queue.
+///
+/// Connections are closed when `shutdown` becomes true.
+async fn tcp_loop(
+    tcp_listener: TcpListener,
+    handler: SharedHandler,
+    permits: Arc<Semaphore>,
+    shutdown: watch::Receiver<bool>,
+) -> Result<(), ApplicationError> {
+    loop {
+        let permit = permits
+            .clone()
+            .acquire_owned()
+            .await
+            .context("The connection semaphore was closed")?;
+
+        let (stream, source) = match tcp_listener.accept().await {
+            Ok(res) => res,
+            Err(e) => {
+                // Such as running out of file descriptors, which may well pass
+                warn!("Error accepting a TCP connection: {e}");
+                tokio::time::sleep(Duration::from_millis(MAX_RECV_BACKOFF_MS)).await;
+                continue;
             }
         };
-    });
+        info!("<= Accepted a TCP connection from {}", source);
+
+        let handler = current(&handler);
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handler
+                .handle_tcp_connection(stream, source, shutdown)
+                .await
+            {
+                warn!("{e}");
+            }
+            drop(permit);
+        });
+    }
 }
@@ -6,9 +6,16 @@
 //!
 //! https://www.rfc-editor.org/rfc/rfc1035#section-3.2
 
-use crate::errors::{QclassError, QtypeError};
+use crate::errors::{EdnsError, NameError};
 use anyhow::Result;
+use deku::ctx::{BitSize, Endian, Limit};
+use deku::no_std_io::{Cursor, Read, Seek, SeekFrom, Write};
 use deku::prelude::*;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
 
 /// # DNS Message
 ///
@@ -16,6 +23,7 @@ use deku::prelude::*;
 /// format called a message.  The top level format of message is divided
 /// into 5 sections (some of which are empty in certain cases) shown below:
 ///
+/// ```text
 ///     +---------------------+
 ///     |        Header       |
 ///     +---------------------+
@@ -27,8 +35,10 @@ use deku::prelude::*;
 ///     +---------------------+
 ///     |      Additional     | RRs holding additional information
 ///     +---------------------+
+/// ```
 ///
-#[derive(Debug, DekuRead, DekuWrite, PartialEq)]
+/// Names are compressed while writing a message, in all of its sections.
+#[derive(Clone, Debug, DekuRead, PartialEq)]
 pub struct Message {
     /// The header
     pub header: Header,
@@ -40,6 +50,60 @@ pub struct Message {
     /// Answers to the questions asked in the question section
     #[deku(count = "header.ancount")]
     pub answer: Vec<ResourceRecord>,
+
+    /// Records that point toward an authoritative name server
+    #[deku(count = "header.nscount")]
+    pub authority: Vec<ResourceRecord>,
+
+    /// Records that relate to the query, but are not strictly answers for the question
+    #[deku(count = "header.arcount")]
+    pub additional: Vec<ResourceRecord>,
+}
+
+impl DekuWriter<()> for Message {
+    fn to_writer<W: Write + Seek>(
+        &self,
+        writer: &mut Writer<W>,
+        _ctx: (),
+    ) -> Result<(), DekuError> {
+        let mut compressor = Compressor::default();
+
+        self.header.to_writer(writer, ())?;
+        for question in &self.question {
+            question.to_writer(writer, &mut compressor)?;
+        }
+        for rr in self
+            .answer
+            .iter()
+            .chain(&self.authority)
+            .chain(&self.additional)
+        {
+            rr.to_writer(writer, &mut compressor)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl DekuContainerWrite for Message {}
+
+impl Message {
+    /// EDNS(0) data of the message, if it has an OPT pseudo-record in its additional section
+    ///
+    /// A message with more than one OPT record, or with one whose name isn't the root, is malformed.
+    pub fn edns(&self) -> Result<Option<Edns>, EdnsError> {
+        let mut opts = self.additional.iter().filter(|rr| rr.type_ == Type::OPT);
+        let Some(opt) = opts.next() else {
+            return Ok(None);
+        };
+        if opts.next().is_some() {
+            return Err(EdnsError::MultipleOpt);
+        }
+        if !opt.name.is_root() {
+            return Err(EdnsError::NotRoot(opt.name.to_string()));
+        }
+        Ok(Edns::from_record(opt))
+    }
 }
 
 /// # DNS Message Header
@@ -51,6 +115,7 @@ pub struct Message {
 ///
 /// A header's length is always 12 bytes.
 ///
+/// ```text
 ///                                     1  1  1  1  1  1
 ///       0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5
 ///     +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
@@ -66,8 +131,9 @@ pub struct Message {
 ///     +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
 ///     |                    ARCOUNT                    |
 ///     +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
+/// ```
 ///
-#[derive(Debug, DekuRead, DekuWrite, PartialEq)]
+#[derive(Clone, Debug, DekuRead, DekuWrite, PartialEq)]
 pub struct Header {
     /// A 16-bit identifier assigned by the program that generates any kind of query.
     /// This identifier is copied into the corresponding reply and can be used by the requester
@@ -129,7 +195,7 @@ pub struct Header {
 }
 
 /// A one-bit field that specifies whether this message is a query (0), or a response (1).
-#[derive(Debug, DekuRead, DekuWrite, PartialEq)]
+#[derive(Clone, Copy, Debug, DekuRead, DekuWrite, PartialEq)]
 #[deku(id_type = "u8", bits = "1")]
 pub enum Qr {
     /// Query
@@ -143,56 +209,458 @@ pub enum Qr {
 
 /// A four-bit field that specifies kind of query in this message.
 /// This value is set by the originator of a query and copied into the response.
-#[derive(Debug, DekuRead, DekuWrite, PartialEq)]
-#[deku(id_type = "u8", bits = "4")]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum OpCode {
     /// a standard query (QUERY)
-    #[deku(id = "0")]
-    Query = 0,
+    Query,
 
     /// an inverse query (IQUERY)
-    #[deku(id = "1")]
-    InverseQuery = 1,
+    InverseQuery,
 
     /// a server status request (STATUS)
-    #[deku(id = "2")]
-    Status = 2,
+    Status,
 
     /// reserved for future use
-    Reserved,
+    Reserved(u8),
+}
+
+impl From<u8> for OpCode {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Self::Query,
+            1 => Self::InverseQuery,
+            2 => Self::Status,
+            v => Self::Reserved(v),
+        }
+    }
+}
+
+impl From<OpCode> for u8 {
+    fn from(value: OpCode) -> Self {
+        match value {
+            OpCode::Query => 0,
+            OpCode::InverseQuery => 1,
+            OpCode::Status => 2,
+            OpCode::Reserved(v) => v,
+        }
+    }
 }
 
 /// Response code - this 4-bit field is set as part of responses.
-#[derive(Debug, DekuRead, DekuWrite, PartialEq)]
-#[deku(id_type = "u8", bits = "4")]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ResponseCode {
     /// No error condition
-    #[deku(id = "0")]
-    NoError = 0,
+    NoError,
 
     /// Format error - The name server was unable to interpret the query.
-    #[deku(id = "1")]
-    FormatError = 1,
+    FormatError,
 
     /// Server failure - The name server was unable to process this query due to a problem with the name server.
-    #[deku(id = "2")]
-    ServerFailure = 2,
+    ServerFailure,
 
     /// Name Error - Meaningful only for responses from an authoritative name server,
     /// this code signifies that the domain name referenced in the query does not exist.
-    #[deku(id = "3")]
-    NameError = 3,
+    NameError,
 
     /// Not Implemented - The name server does not support the requested kind of query.
-    #[deku(id = "4")]
-    NotImplemented = 4,
+    NotImplemented,
 
     /// Refused - The name server refuses to perform the specified operation for policy reasons.
-    #[deku(id = "5")]
-    Refused = 5,
+    Refused,
 
     /// Reserved for future use.
-    Reserved,
+    Reserved(u8),
+}
+
+impl From<u8> for ResponseCode {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Self::NoError,
+            1 => Self::FormatError,
+            2 => Self::ServerFailure,
+            3 => Self::NameError,
+            4 => Self::NotImplemented,
+            5 => Self::Refused,
+            v => Self::Reserved(v),
+        }
+    }
+}
+
+impl From<ResponseCode> for u8 {
+    fn from(value: ResponseCode) -> Self {
+        match value {
+            ResponseCode::NoError => 0,
+            ResponseCode::FormatError => 1,
+            ResponseCode::ServerFailure => 2,
+            ResponseCode::NameError => 3,
+            ResponseCode::NotImplemented => 4,
+            ResponseCode::Refused => 5,
+            ResponseCode::Reserved(v) => v,
+        }
+    }
+}
+
+/// Implements reading and writing of a four-bit header field through its `u8` conversions
+///
+/// Every value of the field is kept, including the reserved ones, so that, for example,
+/// a query with an unknown opcode can still be answered.
+macro_rules! four_bit_field {
+    ($t:ty) => {
+        impl<'a> DekuReader<'a, ()> for $t {
+            fn from_reader_with_ctx<R: Read + Seek>(
+                reader: &mut Reader<R>,
+                _ctx: (),
+            ) -> Result<Self, DekuError> {
+                let value = u8::from_reader_with_ctx(reader, (Endian::Big, BitSize(4)))?;
+                Ok(value.into())
+            }
+        }
+
+        impl DekuWriter<()> for $t {
+            fn to_writer<W: Write + Seek>(
+                &self,
+                writer: &mut Writer<W>,
+                _ctx: (),
+            ) -> Result<(), DekuError> {
+                u8::from(*self).to_writer(writer, (Endian::Big, BitSize(4)))
+            }
+        }
+    };
+}
+
+four_bit_field!(OpCode);
+four_bit_field!(ResponseCode);
+
+/// # Domain name
+///
+/// https://www.rfc-editor.org/rfc/rfc1035#section-3.1
+///
+/// A domain name is represented as a sequence of labels, where each label consists of a length octet
+/// followed by that number of octets.  The domain name terminates with the zero length octet for
+/// the null label of the root.
+///
+/// A name is always kept uncompressed, in its wire format.
+/// While reading, compression pointers are followed, wherever in a message the name is.
+/// Comparison of names is case-insensitive.
+///
+/// https://www.rfc-editor.org/rfc/rfc1035#section-4.1.4
+#[derive(Clone, Debug, Eq)]
+pub struct Name(Vec<u8>);
+
+impl Name {
+    /// Maximum length of a label, without its length octet
+    pub const MAX_LABEL_LEN: usize = 63;
+
+    /// Maximum length of a name in its wire format
+    pub const MAX_NAME_LEN: usize = 255;
+
+    /// The root name
+    pub fn root() -> Self {
+        Self(vec![0])
+    }
+
+    /// Decode a name that starts at `offset` in `buf`, which holds a whole message
+    ///
+    /// Returns the name and the offset of the first byte that follows it in `buf`.
+    pub fn decode(buf: &[u8], offset: usize) -> Result<(Self, usize), NameError> {
+        let mut cursor = Cursor::new(buf);
+        cursor.set_position(offset as u64);
+        let mut reader = Reader::new(&mut cursor);
+        let name = Self::read(&mut reader)?;
+        Ok((name, offset + reader.bits_read / 8))
+    }
+
+    /// The name in its uncompressed wire format
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Labels of the name, from the leftmost one, without the null label of the root
+    pub fn labels(&self) -> Labels<'_> {
+        Labels { rest: &self.0 }
+    }
+
+    /// Is this the root name
+    pub fn is_root(&self) -> bool {
+        self.0.len() == 1
+    }
+
+    /// Is this name `domain` itself, or a name below it
+    ///
+    /// Names are compared label by label, so `www.example.com` is in `example.com`,
+    /// but `www.myexample.com` isn't.
+    pub fn is_subdomain_of(&self, domain: &Name) -> bool {
+        let mut rest = self.as_bytes();
+        loop {
+            if rest.eq_ignore_ascii_case(domain.as_bytes()) {
+                return true;
+            }
+            match rest.first() {
+                Some(&len) if len > 0 => rest = &rest[1 + len as usize..],
+                _ => return false,
+            }
+        }
+    }
+
+    /// The name without its leftmost label, unless this is the root
+    pub fn parent(&self) -> Option<Name> {
+        match self.0[0] as usize {
+            0 => None,
+            len => Some(Self(self.0[1 + len..].to_vec())),
+        }
+    }
+
+    /// This name, taken as relative, followed by `suffix`, as `www` and `example.com` make `www.example.com`
+    pub fn append(&self, suffix: &Name) -> Result<Name, NameError> {
+        let mut name = self.0[..self.0.len() - 1].to_vec();
+        name.extend_from_slice(&suffix.0);
+        if name.len() > Self::MAX_NAME_LEN {
+            return Err(NameError::NameTooLong(name.len()));
+        }
+        Ok(Self(name))
+    }
+
+    /// Read a name, following compression pointers
+    ///
+    /// Every pointer has to point before the previously read part of the name, which rules out
+    /// both forward pointers and pointer loops.
+    /// The position of the reader is restored to right after the first pointer, if there is one.
+    fn read<R: Read + Seek>(reader: &mut Reader<R>) -> Result<Self, NameError> {
+        let mut name = vec![];
+        let mut limit = reader.stream_position()?;
+        let mut resume: Option<(u64, usize)> = None;
+
+        loop {
+            let len = read_u8(reader)?;
+            match len & 0xc0 {
+                0x00 if len == 0 => break,
+                0x00 => {
+                    let len = len as usize;
+                    if name.len() + 1 + len + 1 > Self::MAX_NAME_LEN {
+                        return Err(NameError::NameTooLong(name.len() + 1 + len + 1));
+                    }
+                    let label = read_bytes(reader, len).map_err(|_| NameError::Truncated)?;
+                    name.push(len as u8);
+                    name.extend_from_slice(&label);
+                }
+                0xc0 => {
+                    let here = reader.stream_position()? - 1;
+                    let pointer = u16::from_be_bytes([len & 0x3f, read_u8(reader)?]);
+                    if pointer as u64 >= here {
+                        return Err(NameError::ForwardPointer(pointer));
+                    }
+                    if pointer as u64 >= limit {
+                        return Err(NameError::PointerLoop(pointer));
+                    }
+                    if resume.is_none() {
+                        resume = Some((here + 2, reader.bits_read));
+                    }
+                    limit = pointer as u64;
+                    reader.seek(SeekFrom::Start(limit))?;
+                }
+                _ => return Err(NameError::InvalidLabelType(len)),
+            }
+        }
+        name.push(0);
+
+        if let Some((position, bits_read)) = resume {
+            reader.seek(SeekFrom::Start(position))?;
+            reader.bits_read = bits_read;
+        }
+
+        Ok(Self(name))
+    }
+}
+
+/// Read `len` bytes
+fn read_bytes<R: Read + Seek>(reader: &mut Reader<R>, len: usize) -> Result<Vec<u8>, DekuError> {
+    Vec::<u8>::from_reader_with_ctx(reader, Limit::new_count(len))
+}
+
+/// Read a single byte of a name
+fn read_u8<R: Read + Seek>(reader: &mut Reader<R>) -> Result<u8, NameError> {
+    u8::from_reader_with_ctx(reader, ()).map_err(|_| NameError::Truncated)
+}
+
+impl<'a> DekuReader<'a, ()> for Name {
+    fn from_reader_with_ctx<R: Read + Seek>(
+        reader: &mut Reader<R>,
+        _ctx: (),
+    ) -> Result<Self, DekuError> {
+        Self::read(reader).map_err(|e| DekuError::Parse(e.to_string().into()))
+    }
+}
+
+impl DekuWriter<()> for Name {
+    fn to_writer<W: Write + Seek>(
+        &self,
+        writer: &mut Writer<W>,
+        _ctx: (),
+    ) -> Result<(), DekuError> {
+        writer.write_bytes(&self.0)
+    }
+}
+
+/// Writes a name compressed, as a pointer to an earlier occurrence of its longest suffix, if there is one
+impl DekuWriter<&mut Compressor> for Name {
+    fn to_writer<W: Write + Seek>(
+        &self,
+        writer: &mut Writer<W>,
+        compressor: &mut Compressor,
+    ) -> Result<(), DekuError> {
+        let mut rest = &self.0[..];
+
+        while rest[0] != 0 {
+            let suffix = Name(rest.to_vec());
+            if let Some(&offset) = compressor.offsets.get(&suffix) {
+                return (0xc000 | offset).to_writer(writer, Endian::Big);
+            }
+
+            let offset = writer.bits_written / 8;
+            if offset <= Compressor::MAX_OFFSET {
+                compressor.offsets.insert(suffix, offset as u16);
+            }
+
+            let len = 1 + rest[0] as usize;
+            writer.write_bytes(&rest[..len])?;
+            rest = &rest[len..];
+        }
+
+        writer.write_bytes(&[0])
+    }
+}
+
+/// # Name compression table
+///
+/// Offsets of names that were already written to a message, including all of their suffixes,
+/// so that later occurrences of them can be replaced by pointers.
+///
+/// https://www.rfc-editor.org/rfc/rfc1035#section-4.1.4
+#[derive(Debug, Default)]
+pub struct Compressor {
+    offsets: HashMap<Name, u16>,
+}
+
+impl Compressor {
+    /// Pointers are 14 bits wide, so only names in the first 16 KiB of a message can be pointed to.
+    const MAX_OFFSET: usize = 0x3fff;
+}
+
+impl PartialEq for Name {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.eq_ignore_ascii_case(&other.0)
+    }
+}
+
+impl Hash for Name {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for b in &self.0 {
+            b.to_ascii_lowercase().hash(state);
+        }
+    }
+}
+
+/// Parses a name in its presentation format, such as `example.com.`
+///
+/// The trailing dot is optional, since all names are considered absolute.
+/// A dot inside a label is escaped as `\.`, and an arbitrary byte as `\DDD`.
+impl FromStr for Name {
+    type Err = NameError;
+
+    fn from_str(s: &str) -> Result<Self, NameError> {
+        if s == "." {
+            return Ok(Self::root());
+        }
+
+        let mut name = vec![];
+        let mut label = vec![];
+        let mut chars = s.bytes();
+        let end_label = |label: &mut Vec<u8>, name: &mut Vec<u8>| {
+            if label.is_empty() {
+                return Err(NameError::EmptyLabel(s.to_string()));
+            }
+            if label.len() > Self::MAX_LABEL_LEN {
+                return Err(NameError::LabelTooLong(label.len()));
+            }
+            name.push(label.len() as u8);
+            name.append(label);
+            Ok(())
+        };
+
+        while let Some(c) = chars.next() {
+            match c {
+                b'.' => end_label(&mut label, &mut name)?,
+                b'\\' => match chars.next() {
+                    Some(d) if d.is_ascii_digit() => {
+                        let digits = [Some(d), chars.next(), chars.next()];
+                        let value = digits
+                            .iter()
+                            .try_fold(0u16, |acc, d| match d {
+                                Some(d) if d.is_ascii_digit() => Some(acc * 10 + (d - b'0') as u16),
+                                _ => None,
+                            })
+                            .filter(|v| *v <= 0xff)
+                            .ok_or_else(|| NameError::InvalidEscape(s.to_string()))?;
+                        label.push(value as u8);
+                    }
+                    Some(c) => label.push(c),
+                    None => return Err(NameError::InvalidEscape(s.to_string())),
+                },
+                c => label.push(c),
+            }
+        }
+        if !label.is_empty() {
+            end_label(&mut label, &mut name)?;
+        }
+        name.push(0);
+
+        if name.len() > Self::MAX_NAME_LEN {
+            return Err(NameError::NameTooLong(name.len()));
+        }
+
+        Ok(Self(name))
+    }
+}
+
+/// Formats a name in its presentation format, with a trailing dot
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_root() {
+            return write!(f, ".");
+        }
+        for label in self.labels() {
+            for &b in label {
+                match b {
+                    b'.' | b'\\' | b'"' | b'(' | b')' | b';' | b'@' | b'$' => {
+                        write!(f, "\\{}", b as char)?
+                    }
+                    b'!'..=b'~' => write!(f, "{}", b as char)?,
+                    _ => write!(f, "\\{:03}", b)?,
+                }
+            }
+            write!(f, ".")?;
+        }
+        Ok(())
+    }
+}
+
+/// Iterator over labels of a [`Name`]
+pub struct Labels<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Iterator for Labels<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let len = *self.rest.first()? as usize;
+        if len == 0 {
+            return None;
+        }
+        let label = &self.rest[1..1 + len];
+        self.rest = &self.rest[1 + len..];
+        Some(label)
+    }
 }
 
 /// # DNS Question
@@ -201,6 +669,7 @@ pub enum ResponseCode {
 /// i.e., the parameters that define what is being asked.  The section
 /// contains QDCOUNT (usually 1) entries, each of the following format:
 ///
+/// ```text
 ///                                     1  1  1  1  1  1
 ///       0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5
 ///     +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
@@ -212,8 +681,9 @@ pub enum ResponseCode {
 ///     +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
 ///     |                     QCLASS                    |
 ///     +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
+/// ```
 ///
-#[derive(Debug, DekuRead, DekuWrite, PartialEq)]
+#[derive(Clone, Debug, DekuRead, DekuWrite, PartialEq)]
 pub struct Question {
     /// QNAME:          a domain name represented as a sequence of labels, where
     ///                 each label consists of a length octet followed by that
@@ -221,8 +691,7 @@ pub struct Question {
     ///                 zero length octet for the null label of the root.  Note
     ///                 that this field may be an odd number of octets; no
     ///                 padding is used.
-    #[deku(until = "|v: &u8| *v == 0")]
-    pub qname: Vec<u8>,
+    pub qname: Name,
 
     /// QTYPE:          a two octet code which specifies the type of the query.
     ///                 The values for this field include all codes valid for a
@@ -236,7 +705,7 @@ pub struct Question {
 }
 
 impl Question {
-    pub fn new(qname: Vec<u8>, qtype: Qtype, qclass: Qclass) -> Self {
+    pub fn new(qname: Name, qtype: Qtype, qclass: Qclass) -> Self {
         Self {
             qname,
             qtype,
@@ -245,10 +714,23 @@ impl Question {
     }
 }
 
+impl DekuWriter<&mut Compressor> for Question {
+    fn to_writer<W: Write + Seek>(
+        &self,
+        writer: &mut Writer<W>,
+        compressor: &mut Compressor,
+    ) -> Result<(), DekuError> {
+        self.qname.to_writer(writer, &mut *compressor)?;
+        self.qtype.to_writer(writer, ())?;
+        self.qclass.to_writer(writer, ())
+    }
+}
+
 /// QTYPE fields appear in the question part of a query.  QTYPES are a
 /// superset of TYPEs, hence all TYPEs are valid QTYPEs.
-#[derive(Debug, DekuRead, DekuWrite, PartialEq)]
+#[derive(Clone, Copy, Debug, DekuRead, DekuWrite, Eq, Hash, PartialEq)]
 #[deku(id_type = "u16", bits = "16", endian = "big")]
+#[repr(u16)]
 pub enum Qtype {
     /// a host address
     #[deku(id = "1")]
@@ -258,41 +740,148 @@ pub enum Qtype {
     #[deku(id = "2")]
     NS = 2,
 
+    /// the canonical name for an alias
+    #[deku(id = "5")]
+    CNAME = 5,
+
+    /// marks the start of a zone of authority
+    #[deku(id = "6")]
+    SOA = 6,
+
+    /// a domain name pointer
+    #[deku(id = "12")]
+    PTR = 12,
+
     /// mail exchange
     #[deku(id = "15")]
     MX = 15,
+
+    /// text strings
+    #[deku(id = "16")]
+    TXT = 16,
+
+    /// an IPv6 host address
+    #[deku(id = "28")]
+    AAAA = 28,
+
+    /// location of a service
+    #[deku(id = "33")]
+    SRV = 33,
+
+    /// a request for all records
+    #[deku(id = "255")]
+    ANY = 255,
+
+    /// certification authority authorization
+    #[deku(id = "257")]
+    CAA = 257,
+
+    /// any other type, by its value
+    #[deku(id_pat = "_")]
+    Unknown(u16),
 }
 
-impl TryFrom<u16> for Qtype {
-    type Error = QtypeError;
+impl From<u16> for Qtype {
+    fn from(value: u16) -> Self {
+        match value {
+            1 => Self::A,
+            2 => Self::NS,
+            5 => Self::CNAME,
+            6 => Self::SOA,
+            12 => Self::PTR,
+            15 => Self::MX,
+            16 => Self::TXT,
+            28 => Self::AAAA,
+            33 => Self::SRV,
+            255 => Self::ANY,
+            257 => Self::CAA,
+            v => Self::Unknown(v),
+        }
+    }
+}
 
-    fn try_from(value: u16) -> Result<Qtype, QtypeError> {
+impl From<Qtype> for u16 {
+    fn from(value: Qtype) -> Self {
         match value {
-            1 => Ok(Qtype::A),
-            2 => Ok(Qtype::NS),
-            15 => Ok(Qtype::MX),
-            v => Err(QtypeError::UnsupportedQtype(v)),
+            Qtype::A => 1,
+            Qtype::NS => 2,
+            Qtype::CNAME => 5,
+            Qtype::SOA => 6,
+            Qtype::PTR => 12,
+            Qtype::MX => 15,
+            Qtype::TXT => 16,
+            Qtype::AAAA => 28,
+            Qtype::SRV => 33,
+            Qtype::ANY => 255,
+            Qtype::CAA => 257,
+            Qtype::Unknown(v) => v,
+        }
+    }
+}
+
+/// Formats the query type by its mnemonic, or as `TYPENNN` if it's unknown
+///
+/// https://www.rfc-editor.org/rfc/rfc3597#section-5
+impl fmt::Display for Qtype {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::A => write!(f, "A"),
+            Self::NS => write!(f, "NS"),
+            Self::CNAME => write!(f, "CNAME"),
+            Self::SOA => write!(f, "SOA"),
+            Self::PTR => write!(f, "PTR"),
+            Self::MX => write!(f, "MX"),
+            Self::TXT => write!(f, "TXT"),
+            Self::AAAA => write!(f, "AAAA"),
+            Self::SRV => write!(f, "SRV"),
+            Self::ANY => write!(f, "ANY"),
+            Self::CAA => write!(f, "CAA"),
+            Self::Unknown(v) => write!(f, "TYPE{}", v),
         }
     }
 }
 
 /// QCLASS fields appear in the question section of a query.  QCLASS values
 /// are a superset of CLASS values; every CLASS is a valid QCLASS.
-#[derive(Debug, DekuRead, DekuWrite, PartialEq)]
+#[derive(Clone, Copy, Debug, DekuRead, DekuWrite, Eq, Hash, PartialEq)]
 #[deku(id_type = "u16", bits = "16", endian = "big")]
+#[repr(u16)]
 pub enum Qclass {
     /// the Internet
     #[deku(id = "1")]
     IN = 1,
+
+    /// any other class, by its value
+    #[deku(id_pat = "_")]
+    Unknown(u16),
 }
 
-impl TryFrom<u16> for Qclass {
-    type Error = QclassError;
+impl From<u16> for Qclass {
+    fn from(value: u16) -> Self {
+        match value {
+            1 => Self::IN,
+            v => Self::Unknown(v),
+        }
+    }
+}
 
-    fn try_from(value: u16) -> Result<Qclass, QclassError> {
+impl From<Qclass> for u16 {
+    fn from(value: Qclass) -> Self {
         match value {
-            1 => Ok(Qclass::IN),
-            v => Err(QclassError::UnsupportedQclass(v)),
+            Qclass::IN => 1,
+            Qclass::Unknown(v) => v,
+        }
+    }
+}
+
+/// Formats the query class by its mnemonic, or as `CLASSNNN` if it's unknown
+///
+/// https://www.rfc-editor.org/rfc/rfc3597#section-5
+impl fmt::Display for Qclass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IN => write!(f, "IN"),
+            Self::Unknown(v) => write!(f, "CLASS{}", v),
         }
     }
 }
@@ -304,6 +893,7 @@ impl TryFrom<u16> for Qclass {
 /// records is specified in the corresponding count field in the header.
 /// Each resource record has the following format:
 ///
+/// ```text
 ///                                     1  1  1  1  1  1
 ///       0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5
 ///     +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
@@ -324,12 +914,15 @@ impl TryFrom<u16> for Qclass {
 ///     /                     RDATA                     /
 ///     /                                               /
 ///     +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
+/// ```
+///
 ///
-#[derive(Debug, DekuRead, DekuWrite, PartialEq)]
+/// Domain names that are embedded in RDATA are decompressed while reading,
+/// so a record doesn't depend on the message that it was read from.
+#[derive(Clone, Debug, PartialEq)]
 pub struct ResourceRecord {
     /// NAME:           a domain name to which this resource record pertains.
-    #[deku(until = "|v: &u8| *v == 0")]
-    pub name: Vec<u8>,
+    pub name: Name,
 
     /// TYPE:           two octets containing one of the RR type codes.  This
     ///                 field specifies the meaning of the data in the RDATA
@@ -345,40 +938,538 @@ pub struct ResourceRecord {
     ///                 cached before it should be discarded.  Zero values are
     ///                 interpreted to mean that the RR can only be used for the
     ///                 transaction in progress, and should not be cached.
-    #[deku(endian = "big")]
     pub ttl: u32,
 
-    /// RDLENGTH        an unsigned 16-bit integer that specifies the length in
-    ///                 octets of the RDATA field.
-    #[deku(endian = "big")]
-    pub rdlength: u16,
-
     /// RDATA           a variable-length string of octets that describes the
     ///                 resource.  The format of this information varies
     ///                 according to the TYPE and CLASS of the resource record.
     ///                 For example, if the TYPE is A and the CLASS is IN,
     ///                 the RDATA field is a 4-octet ARPA Internet address.
-    #[deku(count = "rdlength", endian = "big")]
-    pub rdata: Vec<u8>,
+    ///
+    /// RDLENGTH, the length of RDATA, isn't kept, as it is only known once RDATA is written.
+    pub rdata: RData,
 }
 
 impl ResourceRecord {
-    pub fn new(name: Vec<u8>, type_: Type, class: Class, ttl: u32, rdata: Vec<u8>) -> Self {
+    pub fn new(name: Name, type_: Type, class: Class, ttl: u32, rdata: RData) -> Self {
         Self {
             name,
             type_,
             class,
             ttl,
-            rdlength: rdata.len() as u16,
             rdata,
         }
     }
 }
 
+impl<'a> DekuReader<'a, ()> for ResourceRecord {
+    fn from_reader_with_ctx<R: Read + Seek>(
+        reader: &mut Reader<R>,
+        _ctx: (),
+    ) -> Result<Self, DekuError> {
+        let name = Name::from_reader_with_ctx(reader, ())?;
+        let type_ = Type::from_reader_with_ctx(reader, ())?;
+        let class = Class::from_reader_with_ctx(reader, ())?;
+        let ttl = u32::from_reader_with_ctx(reader, Endian::Big)?;
+        let rdlength = u16::from_reader_with_ctx(reader, Endian::Big)?;
+        let rdata = RData::from_reader_with_ctx(reader, (type_, class, rdlength))?;
+
+        Ok(Self::new(name, type_, class, ttl, rdata))
+    }
+}
+
+/// Writes a record with its owner name compressed, as well as the names that are embedded in its RDATA,
+/// if the RDATA of its type is allowed to contain compressed names
+impl DekuWriter<&mut Compressor> for ResourceRecord {
+    fn to_writer<W: Write + Seek>(
+        &self,
+        writer: &mut Writer<W>,
+        compressor: &mut Compressor,
+    ) -> Result<(), DekuError> {
+        self.name.to_writer(writer, &mut *compressor)?;
+        self.type_.to_writer(writer, ())?;
+        self.class.to_writer(writer, ())?;
+        self.ttl.to_writer(writer, Endian::Big)?;
+
+        // RDATA is written aside first, since its length isn't known before it's compressed.
+        let mut cursor = Cursor::new(vec![]);
+        let mut rdata_writer = Writer::new(&mut cursor);
+        // Names inside RDATA start after RDLENGTH.
+        rdata_writer.bits_written = writer.bits_written + 16;
+        self.rdata.to_writer(&mut rdata_writer, compressor)?;
+        let rdata = cursor.into_inner();
+
+        (rdata.len() as u16).to_writer(writer, Endian::Big)?;
+        writer.write_bytes(&rdata)
+    }
+}
+
+/// # RDATA
+///
+/// Data of a resource record, the format of which depends on its type
+///
+/// https://www.rfc-editor.org/rfc/rfc1035#section-3.3
+#[derive(Clone, Debug, PartialEq)]
+pub enum RData {
+    /// A host address
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc1035#section-3.4.1
+    A(Ipv4Addr),
+
+    /// An IPv6 host address
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc3596#section-2.2
+    AAAA(Ipv6Addr),
+
+    /// A host which should be authoritative for the specified class and domain
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc1035#section-3.3.11
+    NS(Name),
+
+    /// The canonical or primary name for the owner; the owner name is an alias
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc1035#section-3.3.1
+    CNAME(Name),
+
+    /// Start of a zone of authority
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc1035#section-3.3.13
+    SOA {
+        /// The name server that was the original or primary source of data for this zone
+        mname: Name,
+        /// The mailbox of the person responsible for this zone
+        rname: Name,
+        /// The version number of the original copy of the zone
+        serial: u32,
+        /// Time interval before the zone should be refreshed
+        refresh: u32,
+        /// Time interval that should elapse before a failed refresh should be retried
+        retry: u32,
+        /// Upper limit on the time interval that can elapse before the zone is no longer authoritative
+        expire: u32,
+        /// Minimum TTL that should be exported with any RR from this zone, and the TTL of negative answers
+        minimum: u32,
+    },
+
+    /// A pointer to some location in the domain name space
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc1035#section-3.3.12
+    PTR(Name),
+
+    /// Mail exchange
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc1035#section-3.3.9
+    MX {
+        /// Preference given to this RR among others at the same owner; lower values are preferred
+        preference: u16,
+        /// A host willing to act as a mail exchange for the owner name
+        exchange: Name,
+    },
+
+    /// One or more character strings
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc1035#section-3.3.14
+    TXT(Vec<Vec<u8>>),
+
+    /// Location of a service
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc2782
+    SRV {
+        /// Priority of the target host; lower values are preferred
+        priority: u16,
+        /// Relative weight for entries with the same priority
+        weight: u16,
+        /// The port on the target host of this service
+        port: u16,
+        /// The domain name of the target host; it is never compressed
+        target: Name,
+    },
+
+    /// Certification Authority Authorization
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc8659#section-4.1
+    CAA {
+        /// Flags; only the Issuer Critical flag, 128, is defined
+        flags: u8,
+        /// The property tag, such as `issue`
+        tag: Vec<u8>,
+        /// The property value
+        value: Vec<u8>,
+    },
+
+    /// Options of an EDNS(0) pseudo-record, see [`Edns`]
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc6891#section-6.1.2
+    OPT(Vec<EdnsOption>),
+
+    /// Data of any other type, kept as is
+    ///
+    /// It's presented in the generic format, as `\\# <length> <hex data>`.
+    Unknown(Vec<u8>),
+}
+
+impl RData {
+    /// Read all EDNS(0) options that make up `len` bytes
+    fn read_options<R: Read + Seek>(
+        reader: &mut Reader<R>,
+        len: usize,
+    ) -> Result<Vec<EdnsOption>, DekuError> {
+        let start = reader.bits_read;
+        let mut options = vec![];
+        while reader.bits_read - start < 8 * len {
+            let code = u16::from_reader_with_ctx(reader, Endian::Big)?;
+            let len = u16::from_reader_with_ctx(reader, Endian::Big)?;
+            let data = read_bytes(reader, len as usize)?;
+            options.push(EdnsOption { code, data });
+        }
+        Ok(options)
+    }
+
+    /// Read all character strings that make up `len` bytes
+    fn read_character_strings<R: Read + Seek>(
+        reader: &mut Reader<R>,
+        len: usize,
+    ) -> Result<Vec<Vec<u8>>, DekuError> {
+        let start = reader.bits_read;
+        let mut strings = vec![];
+        while reader.bits_read - start < 8 * len {
+            let len = u8::from_reader_with_ctx(reader, ())?;
+            strings.push(read_bytes(reader, len as usize)?);
+        }
+        Ok(strings)
+    }
+}
+
+/// Reads RDATA of the given type, class and length
+///
+/// RDATA of unknown types is kept as is, and so is RDATA of address types in classes other than IN,
+/// whose format is specific to the class.
+///
+/// https://www.rfc-editor.org/rfc/rfc3597#section-5
+impl<'a> DekuReader<'a, (Type, Class, u16)> for RData {
+    fn from_reader_with_ctx<R: Read + Seek>(
+        reader: &mut Reader<R>,
+        (type_, class, rdlength): (Type, Class, u16),
+    ) -> Result<Self, DekuError> {
+        let start = reader.bits_read;
+        let rdata = match type_ {
+            Type::A | Type::AAAA if class != Class::IN => {
+                Self::Unknown(read_bytes(reader, rdlength as usize)?)
+            }
+            Type::A => Self::A(u32::from_reader_with_ctx(reader, Endian::Big)?.into()),
+            Type::AAAA => Self::AAAA(u128::from_reader_with_ctx(reader, Endian::Big)?.into()),
+            Type::NS => Self::NS(Name::from_reader_with_ctx(reader, ())?),
+            Type::CNAME => Self::CNAME(Name::from_reader_with_ctx(reader, ())?),
+            Type::SOA => Self::SOA {
+                mname: Name::from_reader_with_ctx(reader, ())?,
+                rname: Name::from_reader_with_ctx(reader, ())?,
+                serial: u32::from_reader_with_ctx(reader, Endian::Big)?,
+                refresh: u32::from_reader_with_ctx(reader, Endian::Big)?,
+                retry: u32::from_reader_with_ctx(reader, Endian::Big)?,
+                expire: u32::from_reader_with_ctx(reader, Endian::Big)?,
+                minimum: u32::from_reader_with_ctx(reader, Endian::Big)?,
+            },
+            Type::PTR => Self::PTR(Name::from_reader_with_ctx(reader, ())?),
+            Type::MX => Self::MX {
+                preference: u16::from_reader_with_ctx(reader, Endian::Big)?,
+                exchange: Name::from_reader_with_ctx(reader, ())?,
+            },
+            Type::TXT => Self::TXT(Self::read_character_strings(reader, rdlength as usize)?),
+            Type::SRV => Self::SRV {
+                priority: u16::from_reader_with_ctx(reader, Endian::Big)?,
+                weight: u16::from_reader_with_ctx(reader, Endian::Big)?,
+                port: u16::from_reader_with_ctx(reader, Endian::Big)?,
+                target: Name::from_reader_with_ctx(reader, ())?,
+            },
+            Type::CAA => {
+                let flags = u8::from_reader_with_ctx(reader, ())?;
+                let tag_len = u8::from_reader_with_ctx(reader, ())?;
+                let tag = read_bytes(reader, tag_len as usize)?;
+                let value_len = (rdlength as usize).checked_sub(2 + tag_len as usize);
+                let value_len = value_len
+                    .ok_or_else(|| DekuError::Parse("CAA tag is longer than RDATA".into()))?;
+                let value = read_bytes(reader, value_len)?;
+                Self::CAA { flags, tag, value }
+            }
+            Type::OPT => Self::OPT(Self::read_options(reader, rdlength as usize)?),
+            Type::Unknown(_) => Self::Unknown(read_bytes(reader, rdlength as usize)?),
+        };
+
+        if reader.bits_read - start != 8 * rdlength as usize {
+            return Err(DekuError::Parse(
+                format!("RDATA of {:?} doesn't match RDLENGTH {}", type_, rdlength).into(),
+            ));
+        }
+
+        Ok(rdata)
+    }
+}
+
+/// Writes RDATA, compressing the embedded names of the types defined in RFC 1035
+///
+/// https://www.rfc-editor.org/rfc/rfc3597#section-4
+impl DekuWriter<&mut Compressor> for RData {
+    fn to_writer<W: Write + Seek>(
+        &self,
+        writer: &mut Writer<W>,
+        compressor: &mut Compressor,
+    ) -> Result<(), DekuError> {
+        match self {
+            Self::A(address) => writer.write_bytes(&address.octets()),
+            Self::AAAA(address) => writer.write_bytes(&address.octets()),
+            Self::NS(name) | Self::CNAME(name) | Self::PTR(name) => {
+                name.to_writer(writer, compressor)
+            }
+            Self::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                mname.to_writer(writer, &mut *compressor)?;
+                rname.to_writer(writer, &mut *compressor)?;
+                for value in [serial, refresh, retry, expire, minimum] {
+                    value.to_writer(writer, Endian::Big)?;
+                }
+                Ok(())
+            }
+            Self::MX {
+                preference,
+                exchange,
+            } => {
+                preference.to_writer(writer, Endian::Big)?;
+                exchange.to_writer(writer, compressor)
+            }
+            Self::TXT(strings) => {
+                for string in strings {
+                    let len = u8::try_from(string.len())?;
+                    len.to_writer(writer, ())?;
+                    writer.write_bytes(string)?;
+                }
+                Ok(())
+            }
+            Self::SRV {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                for value in [priority, weight, port] {
+                    value.to_writer(writer, Endian::Big)?;
+                }
+                target.to_writer(writer, ())
+            }
+            Self::CAA { flags, tag, value } => {
+                flags.to_writer(writer, ())?;
+                u8::try_from(tag.len())?.to_writer(writer, ())?;
+                writer.write_bytes(tag)?;
+                writer.write_bytes(value)
+            }
+            Self::OPT(options) => {
+                for option in options {
+                    option.code.to_writer(writer, Endian::Big)?;
+                    u16::try_from(option.data.len())?.to_writer(writer, Endian::Big)?;
+                    writer.write_bytes(&option.data)?;
+                }
+                Ok(())
+            }
+            Self::Unknown(data) => writer.write_bytes(data),
+        }
+    }
+}
+
+/// Formats RDATA in its presentation format, as in master files
+impl fmt::Display for RData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::A(address) => write!(f, "{}", address),
+            Self::AAAA(address) => write!(f, "{}", address),
+            Self::NS(name) | Self::CNAME(name) | Self::PTR(name) => write!(f, "{}", name),
+            Self::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => write!(
+                f,
+                "{} {} {} {} {} {} {}",
+                mname, rname, serial, refresh, retry, expire, minimum
+            ),
+            Self::MX {
+                preference,
+                exchange,
+            } => write!(f, "{} {}", preference, exchange),
+            Self::TXT(strings) => {
+                for (i, string) in strings.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write_character_string(f, string)?;
+                }
+                Ok(())
+            }
+            Self::SRV {
+                priority,
+                weight,
+                port,
+                target,
+            } => write!(f, "{} {} {} {}", priority, weight, port, target),
+            Self::CAA { flags, tag, value } => {
+                write!(f, "{} {} ", flags, String::from_utf8_lossy(tag))?;
+                write_character_string(f, value)
+            }
+            Self::OPT(options) => {
+                for (i, option) in options.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}:", option.code)?;
+                    option
+                        .data
+                        .iter()
+                        .try_for_each(|b| write!(f, "{:02x}", b))?;
+                }
+                Ok(())
+            }
+            Self::Unknown(data) => {
+                write!(f, "\\# {}", data.len())?;
+                if !data.is_empty() {
+                    write!(f, " ")?;
+                }
+                data.iter().try_for_each(|b| write!(f, "{:02x}", b))
+            }
+        }
+    }
+}
+
+/// Write a character string quoted, escaping the characters that need it
+fn write_character_string(f: &mut fmt::Formatter<'_>, string: &[u8]) -> fmt::Result {
+    write!(f, "\"")?;
+    for &b in string {
+        match b {
+            b'"' | b'\\' => write!(f, "\\{}", b as char)?,
+            b' '..=b'~' => write!(f, "{}", b as char)?,
+            _ => write!(f, "\\{:03}", b)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+/// # EDNS(0)
+///
+/// Extension mechanisms for DNS, which a requester and a responder signal with an OPT pseudo-record
+/// in the additional section of a message.
+/// The fixed fields of the record are reused:
+/// - its name is always the root,
+/// - its CLASS holds the requester's UDP payload size,
+/// - its TTL holds the upper eight bits of the extended RCODE, the version, and the DO flag.
+///
+/// ```text
+///                 +0 (MSB)                            +1 (LSB)
+///      +---+---+---+---+---+---+---+---+---+---+---+---+---+---+---+---+
+///   0: |         EXTENDED-RCODE        |            VERSION            |
+///      +---+---+---+---+---+---+---+---+---+---+---+---+---+---+---+---+
+///   2: | DO|                           Z                               |
+///      +---+---+---+---+---+---+---+---+---+---+---+---+---+---+---+---+
+/// ```
+///
+/// https://www.rfc-editor.org/rfc/rfc6891#section-6.1
+#[derive(Clone, Debug, PartialEq)]
+pub struct Edns {
+    /// The largest UDP payload that the sender can reassemble and deliver
+    pub udp_payload_size: u16,
+
+    /// Upper eight bits of the 12-bit extended RCODE; the lower four are in the header
+    pub extended_rcode: u8,
+
+    /// Version of the implementation
+    pub version: u8,
+
+    /// DNSSEC OK - the sender is able to accept DNSSEC security RRs
+    pub dnssec_ok: bool,
+
+    /// Options, such as cookies or padding
+    pub options: Vec<EdnsOption>,
+}
+
+impl Edns {
+    /// The only version that is defined
+    pub const VERSION: u8 = 0;
+
+    /// The extended RCODE of a response to a query of a version that the responder doesn't implement
+    pub const BADVERS: u16 = 16;
+
+    /// UDP payload sizes below this are treated as equal to it
+    pub const MIN_UDP_PAYLOAD_SIZE: u16 = 512;
+
+    pub fn new(udp_payload_size: u16) -> Self {
+        Self {
+            udp_payload_size,
+            extended_rcode: 0,
+            version: Self::VERSION,
+            dnssec_ok: false,
+            options: vec![],
+        }
+    }
+
+    /// Set the full 12-bit `rcode`, whose lower four bits go to the header's RCODE, `header_rcode`
+    pub fn set_rcode(&mut self, header_rcode: &mut ResponseCode, rcode: u16) {
+        self.extended_rcode = (rcode >> 4) as u8;
+        *header_rcode = ResponseCode::from((rcode & 0xf) as u8);
+    }
+
+    /// The EDNS(0) data of an OPT pseudo-record, or `None` if `rr` isn't one
+    pub fn from_record(rr: &ResourceRecord) -> Option<Self> {
+        let RData::OPT(options) = &rr.rdata else {
+            return None;
+        };
+        Some(Self {
+            udp_payload_size: u16::from(rr.class),
+            extended_rcode: (rr.ttl >> 24) as u8,
+            version: (rr.ttl >> 16) as u8,
+            dnssec_ok: rr.ttl & 0x8000 != 0,
+            options: options.clone(),
+        })
+    }
+
+    /// The OPT pseudo-record that carries this data
+    pub fn to_record(&self) -> ResourceRecord {
+        let ttl = (self.extended_rcode as u32) << 24
+            | (self.version as u32) << 16
+            | if self.dnssec_ok { 0x8000 } else { 0 };
+        ResourceRecord::new(
+            Name::root(),
+            Type::OPT,
+            Class::from(self.udp_payload_size),
+            ttl,
+            RData::OPT(self.options.clone()),
+        )
+    }
+}
+
+/// An EDNS(0) option
+///
+/// https://www.rfc-editor.org/rfc/rfc6891#section-6.1.2
+#[derive(Clone, Debug, PartialEq)]
+pub struct EdnsOption {
+    /// OPTION-CODE, assigned by IANA
+    pub code: u16,
+
+    /// OPTION-DATA, which varies per OPTION-CODE
+    pub data: Vec<u8>,
+}
+
 /// TYPE fields are used in resource records.  Note that these types are a
 /// subset of QTYPEs.
-#[derive(Debug, DekuRead, DekuWrite, PartialEq)]
+#[derive(Clone, Copy, Debug, DekuRead, DekuWrite, Eq, Hash, PartialEq)]
 #[deku(id_type = "u16", bits = "16", endian = "big")]
+#[repr(u16)]
 pub enum Type {
     /// a host address
     #[deku(id = "1")]
@@ -388,17 +1479,536 @@ pub enum Type {
     #[deku(id = "2")]
     NS = 2,
 
+    /// the canonical name for an alias
+    #[deku(id = "5")]
+    CNAME = 5,
+
+    /// marks the start of a zone of authority
+    #[deku(id = "6")]
+    SOA = 6,
+
+    /// a domain name pointer
+    #[deku(id = "12")]
+    PTR = 12,
+
     /// mail exchange
     #[deku(id = "15")]
     MX = 15,
+
+    /// text strings
+    #[deku(id = "16")]
+    TXT = 16,
+
+    /// an IPv6 host address
+    #[deku(id = "28")]
+    AAAA = 28,
+
+    /// location of a service
+    #[deku(id = "33")]
+    SRV = 33,
+
+    /// an EDNS(0) pseudo-record, which only appears in the additional section
+    #[deku(id = "41")]
+    OPT = 41,
+
+    /// certification authority authorization
+    #[deku(id = "257")]
+    CAA = 257,
+
+    /// any other type, by its value
+    #[deku(id_pat = "_")]
+    Unknown(u16),
+}
+
+impl From<u16> for Type {
+    fn from(value: u16) -> Self {
+        match value {
+            1 => Self::A,
+            2 => Self::NS,
+            5 => Self::CNAME,
+            6 => Self::SOA,
+            12 => Self::PTR,
+            15 => Self::MX,
+            16 => Self::TXT,
+            28 => Self::AAAA,
+            33 => Self::SRV,
+            41 => Self::OPT,
+            257 => Self::CAA,
+            v => Self::Unknown(v),
+        }
+    }
+}
+
+impl From<Type> for u16 {
+    fn from(value: Type) -> Self {
+        match value {
+            Type::A => 1,
+            Type::NS => 2,
+            Type::CNAME => 5,
+            Type::SOA => 6,
+            Type::PTR => 12,
+            Type::MX => 15,
+            Type::TXT => 16,
+            Type::AAAA => 28,
+            Type::SRV => 33,
+            Type::OPT => 41,
+            Type::CAA => 257,
+            Type::Unknown(v) => v,
+        }
+    }
+}
+
+/// Formats the type by its mnemonic, or as `TYPENNN` if it's unknown
+///
+/// https://www.rfc-editor.org/rfc/rfc3597#section-5
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::A => write!(f, "A"),
+            Self::NS => write!(f, "NS"),
+            Self::CNAME => write!(f, "CNAME"),
+            Self::SOA => write!(f, "SOA"),
+            Self::PTR => write!(f, "PTR"),
+            Self::MX => write!(f, "MX"),
+            Self::TXT => write!(f, "TXT"),
+            Self::AAAA => write!(f, "AAAA"),
+            Self::SRV => write!(f, "SRV"),
+            Self::OPT => write!(f, "OPT"),
+            Self::CAA => write!(f, "CAA"),
+            Self::Unknown(v) => write!(f, "TYPE{}", v),
+        }
+    }
 }
 
 /// CLASS fields appear in resource records.  Note that these types are a
 /// subset of QCLASSes.
-#[derive(Debug, DekuRead, DekuWrite, PartialEq)]
+#[derive(Clone, Copy, Debug, DekuRead, DekuWrite, Eq, Hash, PartialEq)]
 #[deku(id_type = "u16", bits = "16", endian = "big")]
+#[repr(u16)]
 pub enum Class {
     /// the Internet
     #[deku(id = "1")]
     IN = 1,
+
+    /// any other class, by its value
+    #[deku(id_pat = "_")]
+    Unknown(u16),
+}
+
+impl From<u16> for Class {
+    fn from(value: u16) -> Self {
+        match value {
+            1 => Self::IN,
+            v => Self::Unknown(v),
+        }
+    }
+}
+
+impl From<Class> for u16 {
+    fn from(value: Class) -> Self {
+        match value {
+            Class::IN => 1,
+            Class::Unknown(v) => v,
+        }
+    }
+}
+
+/// Formats the class by its mnemonic, or as `CLASSNNN` if it's unknown
+///
+/// https://www.rfc-editor.org/rfc/rfc3597#section-5
+impl fmt::Display for Class {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IN => write!(f, "IN"),
+            Self::Unknown(v) => write!(f, "CLASS{}", v),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::NameError;
+    use crate::message::{Class, Header, Message, Name, OpCode, Qclass, Qr, Qtype, RData};
+    use crate::message::{Edns, EdnsOption, ResourceRecord, ResponseCode, Type};
+    use deku::{DekuContainerRead, DekuContainerWrite};
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn all_sections_round_trip() {
+        let buf: Vec<u8> = [
+            &[77u8, 77, 129, 128, 0, 1, 0, 1, 0, 1, 0, 1][..],
+            //
+            // Question: "a.com" A IN
+            &[1, 97, 3, 99, 111, 109, 0, 0, 1, 0, 1],
+            //
+            // Answer: "a.com" A IN 60 1.2.3.4
+            &[
+                1, 97, 3, 99, 111, 109, 0, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 1, 2, 3, 4,
+            ],
+            //
+            // Authority: "a.com" NS IN 60 "ns.a.com"
+            &[1, 97, 3, 99, 111, 109, 0, 0, 2, 0, 1, 0, 0, 0, 60, 0, 10],
+            &[2, 110, 115, 1, 97, 3, 99, 111, 109, 0],
+            //
+            // Additional: "ns.a.com" A IN 60 5.6.7.8
+            &[
+                2, 110, 115, 1, 97, 3, 99, 111, 109, 0, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 5, 6, 7, 8,
+            ],
+        ]
+        .concat();
+
+        let (_rest, msg) = Message::from_bytes((&buf, 0)).unwrap();
+
+        assert_eq!(1, msg.answer.len());
+        assert_eq!(1, msg.authority.len());
+        assert_eq!(Type::NS, msg.authority[0].type_);
+        assert_eq!(1, msg.additional.len());
+        assert_eq!(RData::A(Ipv4Addr::new(5, 6, 7, 8)), msg.additional[0].rdata);
+
+        let (_rest, written) = Message::from_bytes((&msg.to_bytes().unwrap(), 0)).unwrap();
+        assert_eq!(msg, written);
+    }
+
+    #[test]
+    fn names_are_compressed_when_writing() {
+        let buf: Vec<u8> = [
+            &[77u8, 77, 129, 128, 0, 1, 0, 1, 0, 1, 0, 1][..],
+            //
+            // 12: Question: "a.com" A IN
+            &[1, 97, 3, 99, 111, 109, 0, 0, 1, 0, 1],
+            //
+            // 23: Answer: "a.com" A IN 60 1.2.3.4
+            &[
+                1, 97, 3, 99, 111, 109, 0, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 1, 2, 3, 4,
+            ],
+            //
+            // 44: Authority: "a.com" NS IN 60 "ns.a.com"
+            &[1, 97, 3, 99, 111, 109, 0, 0, 2, 0, 1, 0, 0, 0, 60, 0, 10],
+            &[2, 110, 115, 1, 97, 3, 99, 111, 109, 0],
+            //
+            // 71: Additional: "ns.a.com" A IN 60 5.6.7.8
+            &[
+                2, 110, 115, 1, 97, 3, 99, 111, 109, 0, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 5, 6, 7, 8,
+            ],
+        ]
+        .concat();
+
+        let (_rest, msg) = Message::from_bytes((&buf, 0)).unwrap();
+
+        let expected: Vec<u8> = [
+            &buf[..23],
+            &[192, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 1, 2, 3, 4],
+            // "ns" is at 51.
+            &[192, 12, 0, 2, 0, 1, 0, 0, 0, 60, 0, 5, 2, 110, 115, 192, 12],
+            &[192, 51, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 5, 6, 7, 8],
+        ]
+        .concat();
+        assert_eq!(expected, msg.to_bytes().unwrap());
+    }
+
+    #[test]
+    fn compressed_names_in_all_places() {
+        let buf: Vec<u8> = [
+            &[77u8, 77, 129, 128, 0, 1, 0, 1, 0, 1, 0, 0][..],
+            //
+            // 12: Question: "a.com" NS IN
+            &[1, 97, 3, 99, 111, 109, 0, 0, 2, 0, 1],
+            //
+            // 23: Answer: "a.com" NS IN 60 "ns.a.com"
+            &[192, 12, 0, 2, 0, 1, 0, 0, 0, 60, 0, 5, 2, 110, 115, 192, 12],
+            //
+            // 40: Authority: "com" MX IN 60 10 "mail.com"
+            &[
+                192, 14, 0, 15, 0, 1, 0, 0, 0, 60, 0, 9, 0, 10, 4, 109, 97, 105, 108, 192, 14,
+            ],
+        ]
+        .concat();
+
+        let (_rest, msg) = Message::from_bytes((&buf, 0)).unwrap();
+
+        assert_eq!("a.com.", msg.answer[0].name.to_string());
+        assert_eq!("ns.a.com.", msg.answer[0].rdata.to_string());
+        assert_eq!("com.", msg.authority[0].name.to_string());
+        assert_eq!("10 mail.com.", msg.authority[0].rdata.to_string());
+    }
+
+    #[test]
+    fn name_decode_errors() {
+        // A pointer to itself
+        let buf = [0u8, 0, 192, 2];
+        assert!(matches!(
+            Name::decode(&buf, 2),
+            Err(NameError::ForwardPointer(2))
+        ));
+
+        // A pointer to a label that leads back to the pointer
+        let buf = [1u8, 97, 192, 0];
+        assert!(matches!(
+            Name::decode(&buf, 0),
+            Err(NameError::PointerLoop(0))
+        ));
+
+        // Two pointers that point to each other
+        let buf = [192u8, 2, 192, 0];
+        assert!(matches!(
+            Name::decode(&buf, 2),
+            Err(NameError::ForwardPointer(2))
+        ));
+
+        let buf = [3u8, 97, 98];
+        assert!(matches!(Name::decode(&buf, 0), Err(NameError::Truncated)));
+
+        let buf = [64u8];
+        assert!(matches!(
+            Name::decode(&buf, 0),
+            Err(NameError::InvalidLabelType(64))
+        ));
+
+        let buf = [1u8, 97, 0, 3, 98, 99, 100, 192, 0, 255];
+        let (name, next) = Name::decode(&buf, 3).unwrap();
+        assert_eq!("bcd.a.", name.to_string());
+        assert_eq!(9, next);
+    }
+
+    #[test]
+    fn name_presentation_format() {
+        let name: Name = "Www.Example.COM".parse().unwrap();
+        assert_eq!("Www.Example.COM.", name.to_string());
+        assert_eq!(name, "www.example.com.".parse().unwrap());
+        assert_eq!(3, name.labels().count());
+
+        let name: Name = r"a\.b\046c.\255".parse().unwrap();
+        assert_eq!(vec![5u8, 97, 46, 98, 46, 99, 1, 255, 0], name.as_bytes());
+        assert_eq!(r"a\.b\.c.\255.", name.to_string());
+
+        assert!(Name::root().is_root());
+        assert_eq!(Name::root(), ".".parse().unwrap());
+        assert!(matches!(
+            "a..b".parse::<Name>(),
+            Err(NameError::EmptyLabel(_))
+        ));
+        assert!(matches!(
+            "a".repeat(64).parse::<Name>(),
+            Err(NameError::LabelTooLong(64))
+        ));
+        assert!(matches!(
+            ["a"; 128].join(".").parse::<Name>(),
+            Err(NameError::NameTooLong(257))
+        ));
+    }
+
+    #[test]
+    fn name_is_subdomain_of() {
+        let name: Name = "www.Corp.Example".parse().unwrap();
+        for domain in ["www.corp.example", "corp.example", "EXAMPLE", "."] {
+            assert!(name.is_subdomain_of(&domain.parse().unwrap()), "{domain}");
+        }
+        for domain in ["orp.example", "www.corp", "a.www.corp.example"] {
+            assert!(!name.is_subdomain_of(&domain.parse().unwrap()), "{domain}");
+        }
+    }
+
+    #[test]
+    fn name_parent_and_append() {
+        let name: Name = "www.corp.example".parse().unwrap();
+        let parent = name.parent().unwrap();
+        assert_eq!("corp.example.", parent.to_string());
+        assert_eq!(
+            Some(Name::root()),
+            "example".parse::<Name>().unwrap().parent()
+        );
+        assert_eq!(None, Name::root().parent());
+
+        let www: Name = "www".parse().unwrap();
+        assert_eq!(name, www.append(&parent).unwrap());
+        assert_eq!(www, www.append(&Name::root()).unwrap());
+        let long: Name = ["a".repeat(63).as_str(); 3].join(".").parse().unwrap();
+        assert!(matches!(long.append(&long), Err(NameError::NameTooLong(_))));
+    }
+
+    #[test]
+    fn typed_rdata_round_trip() {
+        let name: Name = "example.com".parse().unwrap();
+        let rr = |type_, rdata| ResourceRecord::new(name.clone(), type_, Class::IN, 60, rdata);
+        let records = vec![
+            rr(Type::A, RData::A(Ipv4Addr::new(1, 2, 3, 4))),
+            rr(Type::AAAA, RData::AAAA(Ipv6Addr::LOCALHOST)),
+            rr(Type::NS, RData::NS("ns.example.com".parse().unwrap())),
+            rr(
+                Type::CNAME,
+                RData::CNAME("www.example.com".parse().unwrap()),
+            ),
+            rr(
+                Type::SOA,
+                RData::SOA {
+                    mname: "ns.example.com".parse().unwrap(),
+                    rname: "admin.example.com".parse().unwrap(),
+                    serial: 2024120101,
+                    refresh: 7200,
+                    retry: 3600,
+                    expire: 1209600,
+                    minimum: 300,
+                },
+            ),
+            rr(Type::PTR, RData::PTR("host.example.com".parse().unwrap())),
+            rr(
+                Type::MX,
+                RData::MX {
+                    preference: 10,
+                    exchange: "mail.example.com".parse().unwrap(),
+                },
+            ),
+            rr(
+                Type::TXT,
+                RData::TXT(vec![b"v=spf1 -all".to_vec(), b"say \"hi\"".to_vec()]),
+            ),
+            rr(
+                Type::SRV,
+                RData::SRV {
+                    priority: 1,
+                    weight: 2,
+                    port: 5060,
+                    target: "sip.example.com".parse().unwrap(),
+                },
+            ),
+            rr(
+                Type::CAA,
+                RData::CAA {
+                    flags: 0,
+                    tag: b"issue".to_vec(),
+                    value: b"letsencrypt.org".to_vec(),
+                },
+            ),
+        ];
+
+        let msg = Message {
+            header: Header {
+                id: 1,
+                qr: Qr::Response,
+                opcode: OpCode::Query,
+                aa: 1,
+                tc: 0,
+                rd: 0,
+                ra: 0,
+                z: 0,
+                rcode: ResponseCode::NoError,
+                qdcount: 0,
+                ancount: records.len() as u16,
+                nscount: 0,
+                arcount: 0,
+            },
+            question: vec![],
+            answer: records,
+            authority: vec![],
+            additional: vec![],
+        };
+
+        let buf = msg.to_bytes().unwrap();
+        let (_rest, read) = Message::from_bytes((&buf, 0)).unwrap();
+        assert_eq!(msg, read);
+
+        // The SRV target is never compressed, unlike the CNAME target.
+        let srv = [&[0u8, 1, 0, 2, 19, 196, 3][..], b"sip", &[192, 12]].concat();
+        assert!(!buf.windows(srv.len()).any(|w| w == srv));
+        let cname = [&[3u8][..], b"www", &[192, 12]].concat();
+        assert!(buf.windows(cname.len()).any(|w| w == cname));
+
+        let presentation = read
+            .answer
+            .iter()
+            .map(|rr| rr.rdata.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                "1.2.3.4",
+                "::1",
+                "ns.example.com.",
+                "www.example.com.",
+                "ns.example.com. admin.example.com. 2024120101 7200 3600 1209600 300",
+                "host.example.com.",
+                "10 mail.example.com.",
+                "\"v=spf1 -all\" \"say \\\"hi\\\"\"",
+                "1 2 5060 sip.example.com.",
+                "0 issue \"letsencrypt.org\"",
+            ],
+            presentation
+        );
+    }
+
+    #[test]
+    fn unknown_types_and_classes() {
+        let buf: Vec<u8> = [
+            &[77u8, 77, 129, 128, 0, 1, 0, 2, 0, 0, 0, 0][..],
+            //
+            // 12: Question: "a" TYPE65534 CLASS3
+            &[1, 97, 0, 255, 254, 0, 3],
+            //
+            // Answer: "a" TYPE65534 IN 60 \# 3 0a0b0c
+            &[192, 12, 255, 254, 0, 1, 0, 0, 0, 60, 0, 3, 10, 11, 12],
+            //
+            // Answer: "a" A CLASS3 60 \# 2 0102
+            &[192, 12, 0, 1, 0, 3, 0, 0, 0, 60, 0, 2, 1, 2],
+        ]
+        .concat();
+
+        let (_rest, msg) = Message::from_bytes((&buf, 0)).unwrap();
+
+        assert_eq!(Qtype::Unknown(65534), msg.question[0].qtype);
+        assert_eq!(Qclass::Unknown(3), msg.question[0].qclass);
+        assert_eq!("TYPE65534", msg.question[0].qtype.to_string());
+        assert_eq!("CLASS3", msg.question[0].qclass.to_string());
+
+        assert_eq!(Type::Unknown(65534), msg.answer[0].type_);
+        assert_eq!(RData::Unknown(vec![10, 11, 12]), msg.answer[0].rdata);
+        assert_eq!("\\# 3 0a0b0c", msg.answer[0].rdata.to_string());
+
+        assert_eq!(Type::A, msg.answer[1].type_);
+        assert_eq!(Class::Unknown(3), msg.answer[1].class);
+        assert_eq!(RData::Unknown(vec![1, 2]), msg.answer[1].rdata);
+
+        assert_eq!(buf, msg.to_bytes().unwrap());
+
+        assert_eq!(Type::CAA, Type::from(257));
+        assert_eq!(65534, u16::from(Type::Unknown(65534)));
+        assert_eq!("AAAA", Type::AAAA.to_string());
+    }
+
+    #[test]
+    fn edns_round_trip() {
+        let buf: Vec<u8> = [
+            &[77u8, 77, 1, 0, 0, 1, 0, 0, 0, 0, 0, 1][..],
+            //
+            // Question: "a.com" A IN
+            &[1, 97, 3, 99, 111, 109, 0, 0, 1, 0, 1],
+            //
+            // OPT: payload size 4096, extended RCODE 1, version 0, DO, one option 10 (cookie)
+            &[0, 0, 41, 16, 0, 1, 0, 128, 0, 0, 12],
+            &[0, 10, 0, 8, 1, 2, 3, 4, 5, 6, 7, 8],
+        ]
+        .concat();
+
+        let (_rest, msg) = Message::from_bytes((&buf, 0)).unwrap();
+
+        let edns = msg.edns().unwrap().unwrap();
+        assert_eq!(
+            Edns {
+                udp_payload_size: 4096,
+                extended_rcode: 1,
+                version: 0,
+                dnssec_ok: true,
+                options: vec![EdnsOption {
+                    code: 10,
+                    data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+                }],
+            },
+            edns
+        );
+        assert_eq!(msg.additional[0], edns.to_record());
+        assert_eq!(buf, msg.to_bytes().unwrap());
+
+        let mut edns = Edns::new(1232);
+        let mut rcode = ResponseCode::Refused;
+        edns.set_rcode(&mut rcode, Edns::BADVERS);
+        assert_eq!((1, ResponseCode::NoError), (edns.extended_rcode, rcode));
+    }
 }
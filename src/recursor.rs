@@ -0,0 +1,753 @@
+//! # Recursive Resolution
+//!
+//! Resolving questions ourselves, by asking name servers iteratively, from the root servers down
+//! to the name servers of the name, and keeping what we learn on the way
+//!
+//! https://www.rfc-editor.org/rfc/rfc1034#section-5.3.3
+
+use crate::cache::Cache;
+use crate::config::ResolveConfig;
+use crate::constants::{MAX_NAME_SERVER_STATS, MAX_RECURSION_DEPTH, MAX_REFERRALS};
+use crate::errors::{ConfigError, ConnectionError};
+use crate::message::{
+    Header, Message, Name, OpCode, Qclass, Qr, Qtype, Question, RData, ResourceRecord,
+    ResponseCode, Type,
+};
+use crate::selection::{Fastest, Selector, Stats};
+use crate::upstream::exchange;
+use crate::zonefile;
+use anyhow::anyhow;
+use deku::DekuContainerRead;
+use futures::future::{BoxFuture, FutureExt};
+use log::{debug, info};
+use lru::LruCache;
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::time::{timeout, timeout_at};
+
+/// A recursive resolver, which follows referrals from the root servers down
+///
+/// Name servers are asked from the one with the lowest smoothed round-trip time,
+/// and those that haven't been asked yet go first, so that they get measured.
+#[derive(Debug)]
+pub struct Recursor {
+    /// The name servers of the root zone, from the root hints
+    hints: Delegation,
+
+    /// The port that name servers are queried on
+    port: u16,
+
+    /// Time to wait for an answer from a name server
+    timeout: Duration,
+
+    /// The largest UDP payload that we accept, with EDNS(0)
+    max_udp_payload: u16,
+
+    /// Statistics of the name servers that we've asked, by address;
+    /// those of the least recently asked ones make room for new ones
+    stats: Mutex<LruCache<SocketAddr, Stats>>,
+}
+
+/// The name servers of a zone, as far as we know them
+#[derive(Clone, Debug, PartialEq)]
+struct Delegation {
+    /// The zone
+    zone: Name,
+
+    /// The NS records of the zone
+    ns: Vec<ResourceRecord>,
+
+    /// The addresses of the name servers that we know of, as A and AAAA records
+    addresses: Vec<ResourceRecord>,
+}
+
+/// What the response of a name server tells us
+#[derive(Debug, PartialEq)]
+enum Step {
+    /// The answer to the question, which can be NXDOMAIN or NODATA
+    Answer(Message),
+
+    /// A referral to the name servers of a zone that is closer to the name
+    Referral(Delegation),
+}
+
+impl Recursor {
+    /// A recursive resolver according to `config`, unless there are no root hints in it
+    ///
+    /// The root hints are a master file with the NS records of the root zone and the addresses of
+    /// the root servers, such as the `named.root` file that IANA publishes.
+    pub fn new(config: &ResolveConfig, max_udp_payload: u16) -> Result<Option<Self>, ConfigError> {
+        let Some(path) = &config.root_hints else {
+            return Ok(None);
+        };
+        let records = zonefile::read(path, Some(&Name::root()))?;
+        let hints = delegation(Name::root(), &records, &records);
+        if hints.ns.is_empty() {
+            return Err(ConfigError::Invalid(
+                "resolve.root_hints",
+                format!("{} has no NS records for the root", path.display()),
+            ));
+        }
+        if hints.addresses.is_empty() {
+            return Err(ConfigError::Invalid(
+                "resolve.root_hints",
+                format!("{} has no addresses of the root servers", path.display()),
+            ));
+        }
+        info!(
+            "Loaded {} root servers from {}",
+            hints.ns.len(),
+            path.display()
+        );
+
+        Ok(Some(Self {
+            hints,
+            port: config.port,
+            timeout: Duration::from_millis(config.timeout_ms),
+            max_udp_payload,
+            stats: Mutex::new(LruCache::new(
+                NonZeroUsize::new(MAX_NAME_SERVER_STATS).expect("The limit isn't 0"),
+            )),
+        }))
+    }
+
+    /// Statistics of the name servers that we've asked, in no particular order
+    pub fn stats(&self) -> Vec<Stats> {
+        self.lock_stats().iter().map(|(_, s)| s.clone()).collect()
+    }
+
+    /// Take over the statistics of the name servers of `old`, the recursive resolver that this
+    /// one replaces, unless they're queried on another port now
+    pub fn inherit(&self, old: &Recursor) {
+        if self.port != old.port {
+            return;
+        }
+        let old = old.lock_stats();
+        let mut stats = self.lock_stats();
+        for (&address, old) in old.iter().rev() {
+            stats.put(address, old.clone());
+        }
+    }
+
+    /// Resolve a single question, and return the answer of the name servers of its name
+    ///
+    /// The answer comes from the `cache`, if it's there. Otherwise, we start from the name servers
+    /// of the closest zone above the name that we know of, or from the root servers, and follow
+    /// the referrals that we get down to the name servers of the name.
+    /// Everything that we learn on the way goes into the `cache`, if there is one:
+    /// the name servers of the zones, their addresses, and the answer, be it positive or negative.
+    ///
+    /// Aliases aren't followed, so an answer can end with a CNAME record.
+    ///
+    /// However many name servers that takes, it has to be done before the `deadline`.
+    pub async fn resolve(
+        &self,
+        question: &Question,
+        dnssec_ok: bool,
+        cache: Option<&Cache>,
+        deadline: Instant,
+    ) -> Result<Message, ConnectionError> {
+        let iterate = self.iterate(question, dnssec_ok, cache, 0);
+        timeout_at(deadline.into(), iterate)
+            .await
+            .unwrap_or_else(|_| {
+                Err(ConnectionError::Other(anyhow!(
+                    "Resolving {} ran out of time",
+                    question.qname
+                )))
+            })
+    }
+
+    /// Resolve `question`, as [`Recursor::resolve`] does, `depth` levels deep into resolving
+    /// the addresses of name servers
+    fn iterate<'a>(
+        &'a self,
+        question: &'a Question,
+        dnssec_ok: bool,
+        cache: Option<&'a Cache>,
+        depth: usize,
+    ) -> BoxFuture<'a, Result<Message, ConnectionError>> {
+        async move {
+            if let Some(answer) = cache.and_then(|c| c.get(question, dnssec_ok)) {
+                debug!("Answering {} from the cache", question.qname);
+                return Ok(answer);
+            }
+
+            let mut delegation = self.closest(&question.qname, cache);
+            for _ in 0..MAX_REFERRALS {
+                debug!(
+                    "Asking the name servers of {} about {}",
+                    delegation.zone, question.qname
+                );
+                match self
+                    .ask(&delegation, question, dnssec_ok, cache, depth)
+                    .await?
+                {
+                    Step::Answer(answer) => {
+                        if let Some(cache) = cache {
+                            cache.insert(question, dnssec_ok, &answer);
+                        }
+                        return Ok(answer);
+                    }
+                    Step::Referral(next) => {
+                        if let Some(cache) = cache {
+                            next.remember(cache);
+                        }
+                        delegation = next;
+                    }
+                }
+            }
+            Err(ConnectionError::Other(anyhow!(
+                "Resolving {} took more than {} referrals",
+                question.qname,
+                MAX_REFERRALS
+            )))
+        }
+        .boxed()
+    }
+
+    /// The name servers of the closest zone at or above `qname` that are in the `cache`,
+    /// or the root servers if there are none
+    ///
+    /// A zone only counts if we can reach one of its name servers: if the address of one of them
+    /// is in the cache as well, or if one of them is outside of the zone, so that its address can
+    /// be resolved. Otherwise, as when the glue has expired before the NS records, the zone above
+    /// it is tried.
+    fn closest(&self, qname: &Name, cache: Option<&Cache>) -> Delegation {
+        let Some(cache) = cache else {
+            return self.hints.clone();
+        };
+        let cached = |name: &Name, qtype| {
+            let question = Question::new(name.clone(), qtype, Qclass::IN);
+            cache
+                .get(&question, false)
+                .map(|answer| answer.answer)
+                .unwrap_or_default()
+        };
+
+        let mut name = Some(qname.clone());
+        while let Some(zone) = name {
+            let ns = cached(&zone, Qtype::NS);
+            let addresses: Vec<ResourceRecord> = servers(&ns, &zone)
+                .flat_map(|server| [cached(server, Qtype::A), cached(server, Qtype::AAAA)])
+                .flatten()
+                .collect();
+            let delegation = delegation(zone, &ns, &addresses);
+            let reachable = !delegation.addresses.is_empty()
+                || servers(&delegation.ns, &delegation.zone)
+                    .any(|server| !server.is_subdomain_of(&delegation.zone));
+            if reachable {
+                return delegation;
+            }
+            name = delegation.zone.parent();
+        }
+        self.hints.clone()
+    }
+
+    /// Ask the name servers of `delegation` about `question`, until one of them answers usefully
+    ///
+    /// The name servers whose addresses we know are asked first. Only if none of them answers,
+    /// the addresses of the others are resolved, as long as we aren't too deep into resolving
+    /// the addresses of name servers already, and they are asked in turn. Name servers that are
+    /// in the zone itself can't be resolved without its name servers, so they are skipped.
+    async fn ask(
+        &self,
+        delegation: &Delegation,
+        question: &Question,
+        dnssec_ok: bool,
+        cache: Option<&Cache>,
+        depth: usize,
+    ) -> Result<Step, ConnectionError> {
+        let addresses = delegation.addresses.iter().filter_map(address).collect();
+        let mut result = self
+            .ask_at(&delegation.zone, addresses, question, dnssec_ok)
+            .await;
+
+        let unresolved = servers(&delegation.ns, &delegation.zone).filter(|server| {
+            !server.is_subdomain_of(&delegation.zone)
+                && !delegation.addresses.iter().any(|rr| rr.name == **server)
+        });
+        for server in unresolved {
+            if result.is_ok() {
+                break;
+            }
+            if depth >= MAX_RECURSION_DEPTH {
+                return Err(ConnectionError::Other(anyhow!(
+                    "Resolving the name servers of {} goes more than {} levels deep",
+                    delegation.zone,
+                    MAX_RECURSION_DEPTH
+                )));
+            }
+            debug!("Resolving {}, a name server of {}", server, delegation.zone);
+            result = match self.resolve_server(server, cache, depth + 1).await {
+                Ok(addresses) => {
+                    self.ask_at(&delegation.zone, addresses, question, dnssec_ok)
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+        }
+        result
+    }
+
+    /// Ask the name servers of `zone` at `addresses` about `question`, from the fastest,
+    /// until one of them answers usefully
+    async fn ask_at(
+        &self,
+        zone: &Name,
+        addresses: Vec<IpAddr>,
+        question: &Question,
+        dnssec_ok: bool,
+    ) -> Result<Step, ConnectionError> {
+        let mut error = ConnectionError::Other(anyhow!(
+            "No addresses of the name servers of {} are known",
+            zone
+        ));
+        for address in self.fastest(addresses) {
+            match self.query(address, question, dnssec_ok).await {
+                Ok(response) => match step(zone, question, response) {
+                    Ok(step) => return Ok(step),
+                    Err(e) => {
+                        debug!("Name server {} of {} is lame: {}", address, zone, e);
+                        error = e;
+                    }
+                },
+                Err(e) => {
+                    debug!("Failed to ask name server {} of {}: {}", address, zone, e);
+                    error = e;
+                }
+            }
+        }
+        Err(error)
+    }
+
+    /// The addresses of the name server `server`, resolved `depth` levels deep
+    ///
+    /// Its IPv4 addresses are preferred, and its IPv6 addresses are only resolved without them.
+    async fn resolve_server(
+        &self,
+        server: &Name,
+        cache: Option<&Cache>,
+        depth: usize,
+    ) -> Result<Vec<IpAddr>, ConnectionError> {
+        for qtype in [Qtype::A, Qtype::AAAA] {
+            let question = Question::new(server.clone(), qtype, Qclass::IN);
+            let answer = self.iterate(&question, false, cache, depth).await?;
+            let addresses: Vec<IpAddr> = answer
+                .answer
+                .iter()
+                .filter(|rr| rr.name == *server)
+                .filter_map(address)
+                .collect();
+            if !addresses.is_empty() {
+                return Ok(addresses);
+            }
+        }
+        Err(ConnectionError::Other(anyhow!(
+            "The name server {} has no addresses",
+            server
+        )))
+    }
+
+    /// `addresses`, without duplicates, in the order of the smoothed round-trip times of
+    /// the name servers at them, with the port that name servers are queried on
+    fn fastest(&self, mut addresses: Vec<IpAddr>) -> Vec<SocketAddr> {
+        addresses.sort();
+        addresses.dedup();
+        let addresses: Vec<SocketAddr> = addresses
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, self.port))
+            .collect();
+        let stats = {
+            let stats = self.lock_stats();
+            addresses
+                .iter()
+                .map(|&a| stats.peek(&a).cloned().unwrap_or_else(|| Stats::new(a)))
+                .collect::<Vec<_>>()
+        };
+        Fastest
+            .order(&stats)
+            .into_iter()
+            .map(|i| addresses[i])
+            .collect()
+    }
+
+    /// Ask the name server at `address` about `question`, without recursion, and keep track of
+    /// its round-trip time
+    ///
+    /// An answer of SERVFAIL or REFUSED counts as a failure, as does one that is truncated
+    /// even over TCP.
+    async fn query(
+        &self,
+        address: SocketAddr,
+        question: &Question,
+        dnssec_ok: bool,
+    ) -> Result<Message, ConnectionError> {
+        let start = Instant::now();
+        let exchange = exchange(question, address, false, dnssec_ok, self.max_udp_payload);
+        let result = match timeout(self.timeout, exchange).await {
+            Ok(Ok(r_buf)) => Message::from_bytes((&r_buf, 0))
+                .map(|(_rest, response)| response)
+                .map_err(ConnectionError::from),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(ConnectionError::UpstreamError(ErrorKind::TimedOut.into())),
+        };
+        let result = result.and_then(|response| match response.header.rcode {
+            rcode @ (ResponseCode::ServerFailure | ResponseCode::Refused) => Err(
+                ConnectionError::Other(anyhow!("The name server answered {:?}", rcode)),
+            ),
+            _ if response.header.tc == 1 => Err(ConnectionError::Other(anyhow!(
+                "The name server's answer is truncated"
+            ))),
+            _ => Ok(response),
+        });
+
+        self.lock_stats()
+            .get_or_insert_mut(address, || Stats::new(address))
+            .record(start.elapsed(), result.is_err());
+        result
+    }
+
+    /// Lock the statistics of the name servers
+    fn lock_stats(&self) -> MutexGuard<'_, LruCache<SocketAddr, Stats>> {
+        self.stats.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Delegation {
+    /// Keep the NS records of the zone, and the addresses of its name servers, in the `cache`,
+    /// as the answers to the questions about them
+    fn remember(&self, cache: &Cache) {
+        cache.insert(
+            &Question::new(self.zone.clone(), Qtype::NS, Qclass::IN),
+            false,
+            &answer(self.ns.clone()),
+        );
+        for server in servers(&self.ns, &self.zone) {
+            for (type_, qtype) in [(Type::A, Qtype::A), (Type::AAAA, Qtype::AAAA)] {
+                let records: Vec<ResourceRecord> = self
+                    .addresses
+                    .iter()
+                    .filter(|rr| rr.name == *server && rr.type_ == type_)
+                    .cloned()
+                    .collect();
+                if !records.is_empty() {
+                    let question = Question::new(server.clone(), qtype, Qclass::IN);
+                    cache.insert(&question, false, &answer(records));
+                }
+            }
+        }
+    }
+}
+
+/// The delegation to `zone`: its NS records among `ns`, and the addresses of its name servers
+/// among `addresses`
+fn delegation(zone: Name, ns: &[ResourceRecord], addresses: &[ResourceRecord]) -> Delegation {
+    let ns: Vec<ResourceRecord> = ns
+        .iter()
+        .filter(|rr| rr.name == zone && rr.type_ == Type::NS)
+        .cloned()
+        .collect();
+    let addresses = addresses
+        .iter()
+        .filter(|rr| address(rr).is_some() && servers(&ns, &zone).any(|s| *s == rr.name))
+        .cloned()
+        .collect();
+    Delegation {
+        zone,
+        ns,
+        addresses,
+    }
+}
+
+/// The names of the name servers of `zone` among the NS records `ns`
+fn servers<'a>(ns: &'a [ResourceRecord], zone: &'a Name) -> impl Iterator<Item = &'a Name> {
+    ns.iter().filter_map(move |rr| match &rr.rdata {
+        RData::NS(server) if rr.name == *zone => Some(server),
+        _ => None,
+    })
+}
+
+/// The address in `rr`, if it's an A or an AAAA record
+fn address(rr: &ResourceRecord) -> Option<IpAddr> {
+    match rr.rdata {
+        RData::A(ip) => Some(ip.into()),
+        RData::AAAA(ip) => Some(ip.into()),
+        _ => None,
+    }
+}
+
+/// What `response`, from a name server of `zone`, to `question` tells us
+///
+/// The name server is only trusted with names in its zone, its bailiwick, so records of other
+/// names are dropped, whichever section they're in; this keeps a name server from planting
+/// records of names that it isn't responsible for in the cache.
+///
+/// A response that is neither an answer nor a referral to a zone that is closer to the name,
+/// such as a referral back up, makes the name server lame.
+fn step(zone: &Name, question: &Question, mut response: Message) -> Result<Step, ConnectionError> {
+    let in_bailiwick = |rr: &ResourceRecord| rr.name.is_subdomain_of(zone);
+    response.answer.retain(in_bailiwick);
+    response.authority.retain(in_bailiwick);
+    response
+        .additional
+        .retain(|rr| rr.type_ == Type::OPT || in_bailiwick(rr));
+    response.header.ancount = response.answer.len() as u16;
+    response.header.nscount = response.authority.len() as u16;
+    response.header.arcount = response.additional.len() as u16;
+
+    match response.header.rcode {
+        ResponseCode::NoError => {}
+        ResponseCode::NameError => return Ok(Step::Answer(response)),
+        rcode => {
+            return Err(ConnectionError::Other(anyhow!(
+                "The name server answered {:?}",
+                rcode
+            )))
+        }
+    }
+    let soa = response.authority.iter().any(|rr| rr.type_ == Type::SOA);
+    if !response.answer.is_empty() || soa {
+        return Ok(Step::Answer(response));
+    }
+
+    let child = response
+        .authority
+        .iter()
+        .find(|rr| rr.type_ == Type::NS)
+        .map(|rr| rr.name.clone());
+    match child {
+        Some(child) if child != *zone && question.qname.is_subdomain_of(&child) => Ok(
+            Step::Referral(delegation(child, &response.authority, &response.additional)),
+        ),
+        Some(child) => Err(ConnectionError::Other(anyhow!(
+            "The name server referred to {}, which isn't closer to {}",
+            child,
+            question.qname
+        ))),
+        None if response.header.aa == 1 => Ok(Step::Answer(response)),
+        None => Err(ConnectionError::Other(anyhow!(
+            "The name server neither answered nor referred"
+        ))),
+    }
+}
+
+/// A response that answers with `records`, as it's kept in the cache
+fn answer(records: Vec<ResourceRecord>) -> Message {
+    Message {
+        header: Header {
+            id: 0,
+            qr: Qr::Response,
+            opcode: OpCode::Query,
+            aa: 0,
+            tc: 0,
+            rd: 0,
+            ra: 0,
+            z: 0,
+            rcode: ResponseCode::NoError,
+            qdcount: 0,
+            ancount: records.len() as u16,
+            nscount: 0,
+            arcount: 0,
+        },
+        question: vec![],
+        answer: records,
+        authority: vec![],
+        additional: vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::Cache;
+    use crate::config::{CacheConfig, ResolveConfig};
+    use crate::errors::ConfigError;
+    use crate::fixtures::TempDir;
+    use crate::message::{Class, Name, Qclass, Qtype, Question, RData, ResourceRecord};
+    use crate::message::{ResponseCode, Type};
+    use crate::recursor::{answer, delegation, step, Recursor, Step};
+    use std::net::Ipv4Addr;
+    use std::time::{Duration, Instant};
+    use tokio::net::UdpSocket;
+
+    fn rr(name: &str, rdata: RData) -> ResourceRecord {
+        let type_ = match rdata {
+            RData::A(_) => Type::A,
+            RData::NS(_) => Type::NS,
+            RData::CNAME(_) => Type::CNAME,
+            _ => unreachable!(),
+        };
+        ResourceRecord::new(name.parse().unwrap(), type_, Class::IN, 3600, rdata)
+    }
+
+    fn ns(name: &str, server: &str) -> ResourceRecord {
+        rr(name, RData::NS(server.parse().unwrap()))
+    }
+
+    fn a(name: &str, octet: u8) -> ResourceRecord {
+        rr(name, RData::A(Ipv4Addr::new(192, 0, 2, octet)))
+    }
+
+    #[test]
+    fn responses_are_followed_within_the_bailiwick() {
+        let zone: Name = "com".parse().unwrap();
+        let question = Question::new("www.example.com".parse().unwrap(), Qtype::A, Qclass::IN);
+
+        // A referral, with the glue in the bailiwick only
+        let mut response = answer(vec![]);
+        response.authority = vec![
+            ns("example.com", "ns.example.com"),
+            ns("example.com", "ns.example.org"),
+        ];
+        response.additional = vec![a("ns.example.com", 1), a("ns.example.org", 2)];
+        let Ok(Step::Referral(delegation)) = step(&zone, &question, response) else {
+            panic!("Expected a referral");
+        };
+        assert_eq!("example.com.", delegation.zone.to_string());
+        assert_eq!(2, delegation.ns.len());
+        assert_eq!(vec![a("ns.example.com", 1)], delegation.addresses);
+
+        // A referral that isn't closer to the name makes the name server lame.
+        for owner in ["com", "org"] {
+            let mut response = answer(vec![]);
+            response.authority = vec![ns(owner, "ns.example.com")];
+            assert!(step(&zone, &question, response).is_err());
+        }
+
+        // Answers outside of the bailiwick are dropped.
+        let response = answer(vec![
+            rr(
+                "www.example.com",
+                RData::CNAME("www.example.org".parse().unwrap()),
+            ),
+            a("www.example.org", 3),
+        ]);
+        let Ok(Step::Answer(response)) = step(&zone, &question, response) else {
+            panic!("Expected an answer");
+        };
+        let types: Vec<Type> = response.answer.iter().map(|rr| rr.type_).collect();
+        assert_eq!(vec![Type::CNAME], types);
+        assert_eq!(1, response.header.ancount);
+
+        let mut response = answer(vec![]);
+        response.header.rcode = ResponseCode::NameError;
+        assert!(matches!(
+            step(&zone, &question, response),
+            Ok(Step::Answer(_))
+        ));
+        let mut response = answer(vec![]);
+        response.header.rcode = ResponseCode::NotImplemented;
+        assert!(step(&zone, &question, response).is_err());
+    }
+
+    #[test]
+    fn root_hints() {
+        assert!(Recursor::new(&ResolveConfig::default(), 1232)
+            .unwrap()
+            .is_none());
+
+        let dir = TempDir::new("hints", &[]);
+        let load = |contents: &str| {
+            let config = ResolveConfig {
+                root_hints: Some(dir.write("root.hints", contents)),
+                ..Default::default()
+            };
+            Recursor::new(&config, 1232)
+        };
+        let recursor = load(
+            ".                3600000 NS   A.ROOT-SERVERS.NET.\n\
+             A.ROOT-SERVERS.NET. 3600000 A 198.41.0.4\n\
+             A.ROOT-SERVERS.NET. 3600000 AAAA 2001:503:ba3e::2:30\n\
+             B.ROOT-SERVERS.NET. 3600000 A 170.247.170.2\n",
+        );
+        let no_addresses = load(". 3600000 NS A.ROOT-SERVERS.NET.\n");
+        let no_servers = load("A.ROOT-SERVERS.NET. 3600000 A 198.41.0.4\n");
+
+        let recursor = recursor.unwrap().unwrap();
+        assert!(recursor.hints.zone.is_root());
+        assert_eq!(1, recursor.hints.ns.len());
+        assert_eq!(2, recursor.hints.addresses.len());
+        assert!(recursor.stats().is_empty());
+        for result in [no_addresses, no_servers] {
+            assert!(matches!(
+                result,
+                Err(ConfigError::Invalid("resolve.root_hints", _))
+            ));
+        }
+    }
+
+    #[test]
+    fn zones_without_reachable_name_servers_are_passed_over() {
+        let dir = TempDir::new(
+            "closest",
+            &[(
+                "root.hints",
+                ". 3600000 NS ns.root.\nns.root. 3600000 A 127.0.0.1\n",
+            )],
+        );
+        let config = ResolveConfig {
+            root_hints: Some(dir.join("root.hints")),
+            ..Default::default()
+        };
+        let recursor = Recursor::new(&config, 1232).unwrap().unwrap();
+        let cache = Cache::new(&CacheConfig {
+            max_entries: 2,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let qname: Name = "www.example.com".parse().unwrap();
+        let zone: Name = "example.com".parse().unwrap();
+        let in_zone = delegation(
+            zone.clone(),
+            &[ns("example.com", "ns.example.com")],
+            &[a("ns.example.com", 53)],
+        );
+        in_zone.remember(&cache);
+        assert_eq!(in_zone, recursor.closest(&qname, Some(&cache)));
+
+        // The glue is evicted before the NS records, so the root servers are asked again.
+        let ns_question = Question::new(zone.clone(), Qtype::NS, Qclass::IN);
+        assert!(cache.get(&ns_question, false).is_some());
+        let other = Question::new("example.org".parse().unwrap(), Qtype::A, Qclass::IN);
+        cache.insert(&other, false, &answer(vec![a("example.org", 1)]));
+        assert!(cache.get(&ns_question, false).is_some());
+        assert_eq!(recursor.hints, recursor.closest(&qname, Some(&cache)));
+
+        // The address of a name server outside of the zone can be resolved, though.
+        delegation(zone.clone(), &[ns("example.com", "ns.example.net")], &[]).remember(&cache);
+        assert_eq!(zone, recursor.closest(&qname, Some(&cache)).zone);
+    }
+
+    #[tokio::test]
+    async fn resolution_has_a_deadline() {
+        // A root server that never answers
+        let root = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let dir = TempDir::new(
+            "deadline",
+            &[(
+                "root.hints",
+                ". 3600000 NS ns.root.\nns.root. 3600000 A 127.0.0.1\n",
+            )],
+        );
+        let config = ResolveConfig {
+            root_hints: Some(dir.join("root.hints")),
+            port: root.local_addr().unwrap().port(),
+            timeout_ms: 1000,
+            ..Default::default()
+        };
+        let recursor = Recursor::new(&config, 1232).unwrap().unwrap();
+
+        let question = Question::new("example.com".parse().unwrap(), Qtype::A, Qclass::IN);
+        let start = Instant::now();
+        let deadline = start + Duration::from_millis(100);
+        assert!(recursor
+            .resolve(&question, false, None, deadline)
+            .await
+            .is_err());
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+}
@@ -0,0 +1,246 @@
+//! # Upstream Selection
+//!
+//! Policies that decide in which order the upstream resolvers are tried, and how many of them at once
+
+use crate::config::{Strategy, UpstreamsConfig};
+use rand::Rng;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Statistics of an upstream resolver, which selection policies can use
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stats {
+    /// Address of the resolver
+    pub address: SocketAddr,
+
+    /// Smoothed round-trip time, if there has been an attempt at the resolver yet
+    ///
+    /// Attempts that time out count with the timeout.
+    pub srtt: Option<Duration>,
+
+    /// Number of attempts at the resolver
+    pub attempts: u64,
+
+    /// Number of attempts at the resolver that failed
+    pub failures: u64,
+}
+
+impl Stats {
+    /// Statistics of the resolver at `address`, which hasn't been tried yet
+    pub fn new(address: SocketAddr) -> Self {
+        Self {
+            address,
+            srtt: None,
+            attempts: 0,
+            failures: 0,
+        }
+    }
+
+    /// Record an attempt that took `rtt`, and whether it `failed`
+    ///
+    /// The smoothed round-trip time follows TCP's, giving every new sample a weight of 1/8.
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc6298#section-2
+    pub fn record(&mut self, rtt: Duration, failed: bool) {
+        self.srtt = Some(match self.srtt {
+            None => rtt,
+            Some(srtt) => (srtt * 7 + rtt) / 8,
+        });
+        self.attempts += 1;
+        if failed {
+            self.failures += 1;
+        }
+    }
+}
+
+/// A selection policy
+pub trait Selector: Debug + Send + Sync {
+    /// The order in which to try the resolvers with `stats`, as indices into it
+    fn order(&self, stats: &[Stats]) -> Vec<usize>;
+
+    /// Number of resolvers at the front of the order that are queried at the same time,
+    /// taking the first good answer; the rest are tried one by one if they all fail
+    fn parallel(&self) -> usize {
+        1
+    }
+}
+
+/// The selection policy for `config`
+pub fn selector(config: &UpstreamsConfig) -> Box<dyn Selector> {
+    match config.strategy {
+        Strategy::Order => Box::new(InOrder),
+        Strategy::RoundRobin => Box::new(RoundRobin::default()),
+        Strategy::Random => Box::new(WeightedRandom::new(
+            config
+                .resolvers
+                .iter()
+                .copied()
+                .zip(config.weights.iter().copied())
+                .collect(),
+        )),
+        Strategy::Fastest => Box::new(Fastest),
+        Strategy::Race => Box::new(Race::new(config.race_count)),
+    }
+}
+
+/// The resolvers are tried in order of preference, as they are given.
+#[derive(Debug)]
+pub struct InOrder;
+
+impl Selector for InOrder {
+    fn order(&self, stats: &[Stats]) -> Vec<usize> {
+        (0..stats.len()).collect()
+    }
+}
+
+/// Every query starts at the resolver that follows the one that the previous query started at.
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    /// Counter of queries
+    next: AtomicUsize,
+}
+
+impl Selector for RoundRobin {
+    fn order(&self, stats: &[Stats]) -> Vec<usize> {
+        if stats.is_empty() {
+            return vec![];
+        }
+        let first = self.next.fetch_add(1, Ordering::Relaxed) % stats.len();
+        (first..stats.len()).chain(0..first).collect()
+    }
+}
+
+/// Resolvers are picked at random, in proportion to their weights.
+///
+/// The order is a weighted random permutation, so that the resolvers that aren't picked first
+/// still serve as fallbacks. Resolvers without a weight have a weight of 1.
+///
+/// https://doi.org/10.1016/j.ipl.2005.11.003
+#[derive(Debug)]
+pub struct WeightedRandom {
+    /// Weights of the resolvers, by address
+    weights: HashMap<SocketAddr, u32>,
+}
+
+impl WeightedRandom {
+    /// A policy with the given `weights` of the resolvers
+    pub fn new(weights: HashMap<SocketAddr, u32>) -> Self {
+        Self { weights }
+    }
+}
+
+impl Selector for WeightedRandom {
+    fn order(&self, stats: &[Stats]) -> Vec<usize> {
+        let mut rng = rand::thread_rng();
+        // Every resolver gets the key u^(1/w), for a uniform u in [0, 1), and the largest keys go first.
+        let mut keys: Vec<(usize, f64)> = stats
+            .iter()
+            .enumerate()
+            .map(|(i, stats)| {
+                let weight = self.weights.get(&stats.address).copied().unwrap_or(1);
+                let key = match weight {
+                    0 => -1.0,
+                    weight => rng.gen::<f64>().powf(1.0 / weight as f64),
+                };
+                (i, key)
+            })
+            .collect();
+        keys.sort_by(|a, b| b.1.total_cmp(&a.1));
+        keys.into_iter().map(|(i, _key)| i).collect()
+    }
+}
+
+/// Resolvers are tried from the one with the lowest smoothed round-trip time.
+///
+/// Resolvers that haven't been tried yet go first, so that they get measured.
+#[derive(Debug)]
+pub struct Fastest;
+
+impl Selector for Fastest {
+    fn order(&self, stats: &[Stats]) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..stats.len()).collect();
+        order.sort_by_key(|&i| stats[i].srtt);
+        order
+    }
+}
+
+/// The fastest few resolvers are queried at the same time, and the first good answer wins.
+#[derive(Debug)]
+pub struct Race {
+    /// Number of resolvers to query at the same time
+    count: usize,
+}
+
+impl Race {
+    /// A policy that races the `count` fastest resolvers
+    pub fn new(count: usize) -> Self {
+        Self { count }
+    }
+}
+
+impl Selector for Race {
+    fn order(&self, stats: &[Stats]) -> Vec<usize> {
+        Fastest.order(stats)
+    }
+
+    fn parallel(&self) -> usize {
+        self.count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::selection::{Fastest, RoundRobin, Selector, Stats, WeightedRandom};
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    fn stats(n: u16) -> Vec<Stats> {
+        (1..=n)
+            .map(|port| Stats::new(SocketAddr::from(([127, 0, 0, 1], port))))
+            .collect()
+    }
+
+    #[test]
+    fn round_robin() {
+        let selector = RoundRobin::default();
+        let stats = stats(3);
+        assert_eq!(vec![0, 1, 2], selector.order(&stats));
+        assert_eq!(vec![1, 2, 0], selector.order(&stats));
+        assert_eq!(vec![2, 0, 1], selector.order(&stats));
+        assert_eq!(vec![0, 1, 2], selector.order(&stats));
+    }
+
+    #[test]
+    fn fastest() {
+        let mut stats = stats(3);
+        stats[0].record(Duration::from_millis(80), false);
+        stats[1].record(Duration::from_millis(10), false);
+        assert_eq!(vec![2, 1, 0], Fastest.order(&stats));
+
+        // A timeout pulls the average up, but one sample only has a weight of 1/8.
+        stats[1].record(Duration::from_millis(650), true);
+        assert_eq!(Some(Duration::from_millis(90)), stats[1].srtt);
+        assert_eq!((2, 1), (stats[1].attempts, stats[1].failures));
+        assert_eq!(vec![2, 0, 1], Fastest.order(&stats));
+    }
+
+    #[test]
+    fn weighted_random() {
+        let stats = stats(3);
+        let weights = HashMap::from([(stats[0].address, 0), (stats[1].address, 9)]);
+        let selector = WeightedRandom::new(weights);
+
+        let mut firsts = [0; 3];
+        for _ in 0..1000 {
+            let order = selector.order(&stats);
+            assert_eq!(0, order[2]);
+            firsts[order[0]] += 1;
+        }
+        // The odds are 9 to 1.
+        assert!(firsts[1] > 800, "{firsts:?}");
+    }
+}
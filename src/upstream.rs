@@ -0,0 +1,662 @@
+//! # Upstream Resolvers
+//!
+//! Forwarding queries to upstream resolvers, with timeouts, retries, failover, and health tracking;
+//! the resolvers are picked by a policy from [`crate::selection`]
+
+use crate::config::UpstreamsConfig;
+use crate::conn::parse_question;
+use crate::errors::ConnectionError;
+use crate::message::{Edns, Header, Message, OpCode, Qr, Question, ResponseCode};
+use crate::selection::{selector, Selector, Stats};
+use anyhow::anyhow;
+use deku::prelude::*;
+use futures::future::select_ok;
+use log::{info, trace, warn};
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, Interest};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::timeout;
+
+/// The upstream resolvers, in order of preference, and how they are queried
+#[derive(Debug)]
+pub struct Upstreams {
+    /// The resolvers, in order of preference
+    upstreams: Vec<Upstream>,
+
+    /// The policy that picks the resolvers for a query
+    selector: Box<dyn Selector>,
+
+    /// Time to wait for an answer to a single attempt
+    timeout: Duration,
+
+    /// Number of attempts at every resolver, before failing over to the next one
+    attempts: u32,
+
+    /// Number of consecutive failed queries after which a resolver is marked down
+    max_failures: u32,
+
+    /// Time after which a resolver that is down gets a query again, to probe whether it's back up
+    probe_interval: Duration,
+
+    /// The largest UDP payload that we are willing to receive, which we advertise with EDNS(0)
+    max_udp_payload: u16,
+}
+
+/// A single upstream resolver
+#[derive(Debug)]
+struct Upstream {
+    /// Address of the resolver
+    address: SocketAddr,
+
+    /// Health of the resolver, which queries update concurrently
+    health: Mutex<Health>,
+
+    /// Statistics of the resolver, which queries update concurrently
+    stats: Mutex<Stats>,
+}
+
+/// An attempt at an upstream resolver that is under way
+///
+/// If it's dropped before its outcome is recorded, as when another resolver wins a race,
+/// the time that it took until then counts as its round-trip time, which is no less than that of
+/// the winner, so a resolver that doesn't answer doesn't stay untried and keep being raced.
+/// It isn't a failure, as the resolver may only have been slower.
+struct Attempt<'a> {
+    /// The resolver
+    upstream: &'a Upstream,
+
+    /// When the attempt started
+    start: Instant,
+
+    /// Has the outcome of the attempt been recorded
+    recorded: bool,
+}
+
+/// Health of an upstream resolver
+#[derive(Clone, Debug, Default)]
+struct Health {
+    /// Number of queries in a row that the resolver failed to answer
+    consecutive_failures: u32,
+
+    /// When the resolver gets probed next, if it's down
+    probe_at: Option<Instant>,
+}
+
+impl Upstreams {
+    /// Upstream resolvers according to `config`, to which we advertise `max_udp_payload`
+    pub fn new(config: &UpstreamsConfig, max_udp_payload: u16) -> Self {
+        Self {
+            upstreams: config
+                .resolvers
+                .iter()
+                .map(|&address| Upstream {
+                    address,
+                    health: Mutex::new(Health::default()),
+                    stats: Mutex::new(Stats::new(address)),
+                })
+                .collect(),
+            selector: selector(config),
+            timeout: Duration::from_millis(config.timeout_ms),
+            attempts: config.attempts,
+            max_failures: config.max_failures,
+            probe_interval: Duration::from_millis(config.probe_interval_ms),
+            max_udp_payload,
+        }
+    }
+
+    /// Statistics of the resolvers, in order of preference
+    pub fn stats(&self) -> Vec<Stats> {
+        self.upstreams.iter().map(|u| u.stats().clone()).collect()
+    }
+
+    /// Take over the statistics and the health of the resolvers of `old`, the resolvers that these
+    /// replace, which are still among these
+    pub fn inherit(&self, old: &Upstreams) {
+        for upstream in &self.upstreams {
+            if let Some(old) = old.upstreams.iter().find(|u| u.address == upstream.address) {
+                *upstream.stats() = old.stats().clone();
+                *upstream.health() = old.health().clone();
+            }
+        }
+    }
+
+    /// Forward a single question to the resolvers and return the first answer
+    ///
+    /// The resolvers that are up, and those that are down, but due for a probe, are tried
+    /// in the order that the selection policy picks. The policy can have the first few of them
+    /// queried at the same time, and the first good answer wins; the rest are tried one by one.
+    /// If all the resolvers are down, they are all tried anyway.
+    ///
+    /// Every resolver gets a number of attempts, each of which is limited by a timeout;
+    /// an error other than a timeout, such as the resolver being unreachable,
+    /// or an answer of SERVFAIL or REFUSED, moves on to the next resolver right away.
+    ///
+    /// Returns the answer in its wire format, or the last error if none of the resolvers answers.
+    pub async fn query(
+        &self,
+        question: &Question,
+        dnssec_ok: bool,
+    ) -> Result<Vec<u8>, ConnectionError> {
+        let candidates = self.candidates();
+        let stats: Vec<Stats> = candidates.iter().map(|u| u.stats().clone()).collect();
+        let order: Vec<&Upstream> = self
+            .selector
+            .order(&stats)
+            .into_iter()
+            .map(|i| candidates[i])
+            .collect();
+
+        let parallel = self.selector.parallel().clamp(1, order.len().max(1));
+        let (racers, rest) = order.split_at(parallel.min(order.len()));
+
+        let mut error = ConnectionError::Other(anyhow!("No resolvers to forward to"));
+        if !racers.is_empty() {
+            let racers = racers
+                .iter()
+                .map(|upstream| Box::pin(self.try_upstream(upstream, question, dnssec_ok)));
+            match select_ok(racers).await {
+                Ok((r_buf, _rest)) => return Ok(r_buf),
+                Err(e) => error = e,
+            }
+        }
+        for upstream in rest {
+            match self.try_upstream(upstream, question, dnssec_ok).await {
+                Ok(r_buf) => return Ok(r_buf),
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+
+    /// Query a single resolver, `upstream`, and keep track of its health
+    async fn try_upstream(
+        &self,
+        upstream: &Upstream,
+        question: &Question,
+        dnssec_ok: bool,
+    ) -> Result<Vec<u8>, ConnectionError> {
+        match self.query_one(upstream, question, dnssec_ok).await {
+            Ok(r_buf) => {
+                self.succeeded(upstream);
+                Ok(r_buf)
+            }
+            Err(e) => {
+                warn!("Failed to forward to {}: {}", upstream.address, e);
+                self.failed(upstream);
+                Err(e)
+            }
+        }
+    }
+
+    /// Query a single resolver, `upstream`, with retries on timeouts
+    ///
+    /// The round-trip time of every attempt goes into the statistics of the resolver.
+    /// An attempt that is cut short, because another resolver won a race, counts with the time
+    /// that it took until then, but not as a failure.
+    async fn query_one(
+        &self,
+        upstream: &Upstream,
+        question: &Question,
+        dnssec_ok: bool,
+    ) -> Result<Vec<u8>, ConnectionError> {
+        for attempt in 1..=self.attempts {
+            let pending = Attempt::new(upstream);
+            let exchange = exchange(
+                question,
+                upstream.address,
+                true,
+                dnssec_ok,
+                self.max_udp_payload,
+            );
+            match timeout(self.timeout, exchange).await {
+                Ok(Ok(r_buf)) => {
+                    let (_rest, rheader) = Header::from_bytes((&r_buf, 0))?;
+                    let failed = matches!(
+                        rheader.rcode,
+                        ResponseCode::ServerFailure | ResponseCode::Refused
+                    );
+                    pending.finish(failed);
+                    if failed {
+                        return Err(ConnectionError::Other(anyhow!(
+                            "The resolver answered {:?}",
+                            rheader.rcode
+                        )));
+                    }
+                    return Ok(r_buf);
+                }
+                Ok(Err(e)) => {
+                    pending.finish(true);
+                    return Err(e);
+                }
+                Err(_) => {
+                    pending.record(self.timeout, true);
+                    trace!(
+                        "Attempt {} at {} timed out after {:?}",
+                        attempt,
+                        upstream.address,
+                        self.timeout
+                    );
+                }
+            }
+        }
+        Err(ConnectionError::UpstreamError(ErrorKind::TimedOut.into()))
+    }
+
+    /// The resolvers to try, in order of preference
+    fn candidates(&self) -> Vec<&Upstream> {
+        let now = Instant::now();
+        let candidates: Vec<&Upstream> = self
+            .upstreams
+            .iter()
+            .filter(|upstream| {
+                let mut health = upstream.health();
+                match health.probe_at {
+                    None => true,
+                    Some(probe_at) if probe_at <= now => {
+                        // Only a single query probes the resolver, until the next interval.
+                        health.probe_at = Some(now + self.probe_interval);
+                        true
+                    }
+                    Some(_) => false,
+                }
+            })
+            .collect();
+
+        if candidates.is_empty() {
+            self.upstreams.iter().collect()
+        } else {
+            candidates
+        }
+    }
+
+    /// Record that `upstream` answered
+    fn succeeded(&self, upstream: &Upstream) {
+        let mut health = upstream.health();
+        if health.probe_at.is_some() {
+            info!("Upstream resolver {} is back up", upstream.address);
+        }
+        *health = Health::default();
+    }
+
+    /// Record that `upstream` failed to answer, and mark it down if it's failed too many times in a row
+    fn failed(&self, upstream: &Upstream) {
+        let mut health = upstream.health();
+        health.consecutive_failures += 1;
+        if health.consecutive_failures >= self.max_failures {
+            if health.probe_at.is_none() {
+                warn!(
+                    "Marking upstream resolver {} down after {} failures in a row",
+                    upstream.address, health.consecutive_failures
+                );
+            }
+            health.probe_at = Some(Instant::now() + self.probe_interval);
+        }
+    }
+}
+
+impl<'a> Attempt<'a> {
+    /// An attempt at `upstream`, which starts now
+    fn new(upstream: &'a Upstream) -> Self {
+        Self {
+            upstream,
+            start: Instant::now(),
+            recorded: false,
+        }
+    }
+
+    /// Record that the attempt has ended now, and whether it `failed`
+    fn finish(self, failed: bool) {
+        let rtt = self.start.elapsed();
+        self.record(rtt, failed);
+    }
+
+    /// Record that the attempt took `rtt`, and whether it `failed`
+    fn record(mut self, rtt: Duration, failed: bool) {
+        self.upstream.stats().record(rtt, failed);
+        self.recorded = true;
+    }
+}
+
+impl Drop for Attempt<'_> {
+    fn drop(&mut self) {
+        if !self.recorded {
+            trace!("Attempt at {} was cut short", self.upstream.address);
+            self.upstream.stats().record(self.start.elapsed(), false);
+        }
+    }
+}
+
+impl Upstream {
+    /// Lock the health of the resolver
+    fn health(&self) -> MutexGuard<'_, Health> {
+        self.health.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Lock the statistics of the resolver
+    fn stats(&self) -> MutexGuard<'_, Stats> {
+        self.stats.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Send a single question to the upstream `resolver` and return its answer
+///
+/// The answer is returned in its wire format, as received from the resolver.
+///
+/// Every call talks to the resolver through its own socket, of the resolver's address family,
+/// bound to an ephemeral port, which is
+/// picked at random by the OS, and uses a random message ID, so that answers are hard to spoof.
+/// The socket is connected to the resolver, so it only receives datagrams from the resolver,
+/// and learns of it being unreachable.
+/// Only a reply that carries the same ID and question as the query is accepted;
+/// anything else that arrives on the socket is discarded.
+/// If the reply is truncated, the query is sent again over TCP, and that answer is returned.
+///
+/// We ask the resolver to pursue the query recursively if `recursion_desired`, regardless of what
+/// the client asked, and advertise our UDP payload size, `max_udp_payload`, to it with EDNS(0).
+/// Name servers that we resolve through ourselves are asked iteratively instead.
+///
+/// There is no timeout; the caller sets one.
+pub(crate) async fn exchange(
+    question: &Question,
+    resolver: SocketAddr,
+    recursion_desired: bool,
+    dnssec_ok: bool,
+    max_udp_payload: u16,
+) -> Result<Vec<u8>, ConnectionError> {
+    let unspecified = match resolver {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let upstream_socket = UdpSocket::bind(unspecified)
+        .await
+        .map_err(ConnectionError::UpstreamError)?;
+    upstream_socket
+        .connect(resolver)
+        .await
+        .map_err(ConnectionError::UpstreamError)?;
+
+    let mut edns = Edns::new(max_udp_payload);
+    edns.dnssec_ok = dnssec_ok;
+
+    let id = rand::random::<u16>();
+    let qmsg = Message {
+        header: Header {
+            id,
+            qr: Qr::Query,
+            opcode: OpCode::Query,
+            aa: 0,
+            tc: 0,
+            rd: u8::from(recursion_desired),
+            ra: 0,
+            z: 0,
+            rcode: ResponseCode::NoError,
+            qdcount: 1,
+            ancount: 0,
+            nscount: 0,
+            arcount: 1,
+        },
+        question: vec![question.clone()],
+        answer: vec![],
+        authority: vec![],
+        additional: vec![edns.to_record()],
+    };
+
+    // Send a Question message
+    let q_buf = qmsg.to_bytes()?;
+    upstream_socket
+        .send(&q_buf)
+        .await
+        .map_err(ConnectionError::UpstreamError)?;
+    trace!("=> Forwarded query {} to {}", id, resolver);
+
+    // Receive an Answer message
+    let mut r_buf = vec![0u8; max_udp_payload as usize];
+    loop {
+        let received = recv_connected(&upstream_socket, &mut r_buf)
+            .await
+            .map_err(ConnectionError::UpstreamError)?;
+
+        let r_buf = &r_buf[..received];
+        let rheader = match check_reply(r_buf, &qmsg) {
+            Ok(rheader) => rheader,
+            Err(e) => {
+                warn!("Discarding a reply from {}: {}", resolver, e);
+                continue;
+            }
+        };
+
+        trace!("<= Received answer {} from {}", id, resolver);
+        if rheader.tc == 0 {
+            return Ok(r_buf.to_vec());
+        }
+        break;
+    }
+
+    // The answer didn't fit in a datagram, so ask again over TCP.
+    trace!(
+        "=> Retrying truncated query {} to {} over TCP",
+        id,
+        resolver
+    );
+    exchange_tcp(&q_buf, &qmsg, resolver)
+        .await
+        .map_err(ConnectionError::UpstreamError)
+}
+
+/// Send the query `q_buf`, which is `qmsg` in its wire format, to the upstream `resolver` over TCP,
+/// and return its answer
+async fn exchange_tcp(
+    q_buf: &[u8],
+    qmsg: &Message,
+    resolver: SocketAddr,
+) -> std::io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(resolver).await?;
+    stream
+        .write_all(&(q_buf.len() as u16).to_be_bytes())
+        .await?;
+    stream.write_all(q_buf).await?;
+
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len).await?;
+    let mut r_buf = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut r_buf).await?;
+
+    check_reply(&r_buf, qmsg).map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+    trace!(
+        "<= Received answer {} from {} over TCP",
+        qmsg.header.id,
+        resolver
+    );
+    Ok(r_buf)
+}
+
+/// Check that `r_buf` is a reply to `qmsg`, that is, that it carries the same ID and question,
+/// and return its header
+fn check_reply(r_buf: &[u8], qmsg: &Message) -> Result<Header, String> {
+    let (_rest, rheader) =
+        Header::from_bytes((r_buf, 0)).map_err(|e| format!("malformed reply: {e}"))?;
+    if rheader.id != qmsg.header.id || rheader.qr != Qr::Response || rheader.qdcount != 1 {
+        return Err(format!("reply doesn't match query {}", qmsg.header.id));
+    }
+
+    let mut rquestions = vec![];
+    parse_question(r_buf, &r_buf[12..], &rheader, &mut rquestions)
+        .map_err(|e| format!("malformed reply: {e}"))?;
+    if rquestions != qmsg.question {
+        return Err(format!("reply doesn't match query {}", qmsg.header.id));
+    }
+
+    Ok(rheader)
+}
+
+/// Receive a datagram on a connected `socket`
+///
+/// Unlike [`UdpSocket::recv`], this also returns the errors that are reported asynchronously,
+/// such as the port of the peer being unreachable, which tokio only signals as error readiness.
+async fn recv_connected(socket: &UdpSocket, buf: &mut [u8]) -> std::io::Result<usize> {
+    loop {
+        let ready = socket.ready(Interest::READABLE | Interest::ERROR).await?;
+        if ready.is_error() {
+            if let Some(e) = socket.take_error()? {
+                return Err(e);
+            }
+            // The error has already been taken, so the readiness is stale.
+            let _ = socket.try_io(Interest::ERROR, || {
+                Err::<(), _>(ErrorKind::WouldBlock.into())
+            });
+        }
+        match socket.try_recv(buf) {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+            res => return res,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Strategy, UpstreamsConfig};
+    use crate::message::{Message, Qclass, Qr, Qtype, Question, ResponseCode};
+    use crate::upstream::Upstreams;
+    use deku::{DekuContainerRead, DekuContainerWrite};
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+    use tokio::net::UdpSocket;
+
+    /// A resolver that answers every query with `rcode`, and never answers if there is none
+    async fn fake_resolver(rcode: Option<ResponseCode>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let (received, source) = socket.recv_from(&mut buf).await.unwrap();
+                let Some(rcode) = rcode else {
+                    continue;
+                };
+                let (_rest, mut msg) = Message::from_bytes((&buf[..received], 0)).unwrap();
+                msg.header.qr = Qr::Response;
+                msg.header.rcode = rcode;
+                msg.header.arcount = 0;
+                msg.additional.clear();
+                let r_buf = msg.to_bytes().unwrap();
+                socket.send_to(&r_buf, source).await.unwrap();
+            }
+        });
+        address
+    }
+
+    fn config(resolvers: Vec<SocketAddr>) -> UpstreamsConfig {
+        UpstreamsConfig {
+            resolvers,
+            timeout_ms: 50,
+            attempts: 2,
+            max_failures: 2,
+            probe_interval_ms: 200,
+            ..Default::default()
+        }
+    }
+
+    fn question() -> Question {
+        Question::new("example.com".parse().unwrap(), Qtype::A, Qclass::IN)
+    }
+
+    #[tokio::test]
+    async fn fails_over_and_marks_down() {
+        let silent = fake_resolver(None).await;
+        let refusing = fake_resolver(Some(ResponseCode::Refused)).await;
+        let good = fake_resolver(Some(ResponseCode::NameError)).await;
+        let upstreams = Upstreams::new(&config(vec![silent, refusing, good]), 1232);
+
+        for _ in 0..2 {
+            let r_buf = upstreams.query(&question(), false).await.unwrap();
+            let (_rest, rmsg) = Message::from_bytes((&r_buf, 0)).unwrap();
+            assert_eq!(ResponseCode::NameError, rmsg.header.rcode);
+        }
+        let candidates: Vec<SocketAddr> =
+            upstreams.candidates().iter().map(|u| u.address).collect();
+        assert_eq!(vec![good], candidates);
+
+        // Once the probe interval passes, the resolvers that are down get another chance.
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert_eq!(3, upstreams.candidates().len());
+        assert_eq!(1, upstreams.candidates().len());
+    }
+
+    #[tokio::test]
+    async fn all_resolvers_fail() {
+        let silent = fake_resolver(None).await;
+        let upstreams = Upstreams::new(&config(vec![silent]), 1232);
+
+        for _ in 0..3 {
+            assert!(upstreams.query(&question(), false).await.is_err());
+        }
+        // Even though it's down, the only resolver is still tried.
+        assert_eq!(1, upstreams.candidates().len());
+    }
+
+    #[tokio::test]
+    async fn races_resolvers() {
+        let silent = fake_resolver(None).await;
+        let good = fake_resolver(Some(ResponseCode::NoError)).await;
+        let mut config = config(vec![silent, good]);
+        config.timeout_ms = 1000;
+        config.strategy = Strategy::Race;
+        let upstreams = Upstreams::new(&config, 1232);
+
+        let start = Instant::now();
+        upstreams.query(&question(), false).await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        // The silent resolver's attempt was cut short, so it counts as slower, but not as a failure.
+        let stats = upstreams.stats();
+        assert_eq!((1, 0), (stats[0].attempts, stats[0].failures));
+        assert_eq!((1, 0), (stats[1].attempts, stats[1].failures));
+        assert!(stats[0].srtt >= stats[1].srtt && stats[1].srtt.is_some());
+    }
+
+    #[tokio::test]
+    async fn silent_resolvers_stop_being_raced() {
+        let silent = fake_resolver(None).await;
+        let good = fake_resolver(Some(ResponseCode::NoError)).await;
+        let other = fake_resolver(Some(ResponseCode::NoError)).await;
+        let mut config = config(vec![silent, good, other]);
+        config.timeout_ms = 1000;
+        config.strategy = Strategy::Race;
+        config.race_count = 2;
+        let upstreams = Upstreams::new(&config, 1232);
+
+        for _ in 0..2 {
+            upstreams.query(&question(), false).await.unwrap();
+        }
+
+        // Only the first query raced the silent resolver; then it was slower than the winner,
+        // and the third resolver hadn't been tried yet.
+        // Losing races isn't an error.
+        let stats = upstreams.stats();
+        assert_eq!(1, stats[0].attempts);
+        assert!(stats.iter().all(|s| s.srtt.is_some() && s.failures == 0));
+    }
+
+    #[tokio::test]
+    async fn stats_and_health_are_inherited() {
+        let silent = fake_resolver(None).await;
+        let good = fake_resolver(Some(ResponseCode::NoError)).await;
+        let old = Upstreams::new(&config(vec![silent, good]), 1232);
+        for _ in 0..2 {
+            old.query(&question(), false).await.unwrap();
+        }
+
+        // Only the resolvers that are still there keep what's known about them.
+        let other = fake_resolver(Some(ResponseCode::NoError)).await;
+        let upstreams = Upstreams::new(&config(vec![silent, other]), 1232);
+        upstreams.inherit(&old);
+        assert_eq!(old.stats()[0], upstreams.stats()[0]);
+        assert!(upstreams.stats()[1].srtt.is_none());
+        let candidates: Vec<SocketAddr> =
+            upstreams.candidates().iter().map(|u| u.address).collect();
+        assert_eq!(vec![other], candidates);
+    }
+}
@@ -0,0 +1,498 @@
+//! # Zones
+//!
+//! Zones that we are authoritative for, kept in memory, and the answers to questions about them
+//!
+//! https://www.rfc-editor.org/rfc/rfc1034#section-4.3.2
+
+use crate::config::ZoneConfig;
+use crate::errors::ZoneError;
+use crate::message::{Name, Qtype, RData, ResourceRecord, ResponseCode, Type};
+use crate::zonefile;
+use log::info;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// A zone, with all of its records
+///
+/// The records are kept by owner name, in a tree of the names in the zone: every name between
+/// an owner and the origin is in it too, without records if it owns none, as an empty non-terminal.
+/// Such a name exists, so a question about it gets NODATA rather than NXDOMAIN.
+#[derive(Debug)]
+pub struct Zone {
+    /// The name at the top of the zone, which owns its SOA record
+    origin: Name,
+
+    /// The records, by owner name
+    nodes: HashMap<Name, Vec<ResourceRecord>>,
+}
+
+/// The answer to a question from a zone, which makes up the sections of the response
+///
+/// Other answers, such as those of upstream resolvers, take the same shape when they're merged.
+#[derive(Debug, PartialEq)]
+pub struct Lookup {
+    /// NOERROR, or NXDOMAIN if the name doesn't exist
+    pub rcode: ResponseCode,
+
+    /// The upper eight bits of an extended RCODE, which only answers from upstream can have
+    pub extended_rcode: u8,
+
+    /// Is the answer authoritative, which it isn't if it's a referral
+    pub authoritative: bool,
+
+    /// Records that answer the question
+    pub answer: Vec<ResourceRecord>,
+
+    /// The SOA record of the zone if there is no answer, which tells how long that may be cached,
+    /// or the NS records of the delegation in a referral
+    pub authority: Vec<ResourceRecord>,
+
+    /// Addresses of the name servers of the delegation in a referral, which are in the zone
+    pub additional: Vec<ResourceRecord>,
+}
+
+impl Zone {
+    /// The zone of `config`, read from its master file
+    ///
+    /// Its origin is the owner of its SOA record, unless the configuration gives it.
+    pub fn load(config: &ZoneConfig) -> Result<Self, ZoneError> {
+        let records = zonefile::read(&config.file, config.origin.as_ref())?;
+        let origin = match &config.origin {
+            Some(origin) => origin.clone(),
+            None => records
+                .iter()
+                .find(|rr| rr.type_ == Type::SOA)
+                .map(|rr| rr.name.clone())
+                .ok_or_else(|| {
+                    ZoneError::Invalid(
+                        config.file.display().to_string(),
+                        "there is no SOA record".to_string(),
+                    )
+                })?,
+        };
+        let len = records.len();
+        let zone = Self::new(origin, records)?;
+        info!(
+            "Loaded the zone {} from {}, with {} records",
+            zone.origin,
+            config.file.display(),
+            len
+        );
+        Ok(zone)
+    }
+
+    /// The zone at `origin`, with `records`
+    ///
+    /// Every record has to be in the zone, and the zone has to have exactly one SOA record,
+    /// at its origin. A name with a CNAME record can't have other records.
+    /// Records that are given more than once are kept once.
+    pub fn new(origin: Name, records: Vec<ResourceRecord>) -> Result<Self, ZoneError> {
+        let invalid = |message| ZoneError::Invalid(origin.to_string(), message);
+
+        let mut nodes: HashMap<Name, Vec<ResourceRecord>> =
+            HashMap::from([(origin.clone(), vec![])]);
+        for rr in records {
+            if !rr.name.is_subdomain_of(&origin) {
+                return Err(invalid(format!("{} is outside of the zone", rr.name)));
+            }
+            let mut ancestor = rr.name.parent();
+            while let Some(name) = ancestor.filter(|name| !nodes.contains_key(name)) {
+                ancestor = name.parent();
+                nodes.insert(name, vec![]);
+            }
+            let node = nodes.entry(rr.name.clone()).or_default();
+            if !node
+                .iter()
+                .any(|other| other.type_ == rr.type_ && other.rdata == rr.rdata)
+            {
+                node.push(rr);
+            }
+        }
+
+        let soas: Vec<&Name> = nodes
+            .values()
+            .flatten()
+            .filter(|rr| rr.type_ == Type::SOA)
+            .map(|rr| &rr.name)
+            .collect();
+        match soas.as_slice() {
+            [owner] if **owner == origin => {}
+            [owner] => return Err(invalid(format!("the SOA record is at {owner}"))),
+            [] => return Err(invalid("there is no SOA record".to_string())),
+            _ => return Err(invalid("there is more than one SOA record".to_string())),
+        }
+        for (name, records) in &nodes {
+            if records.len() > 1 && records.iter().any(|rr| rr.type_ == Type::CNAME) {
+                return Err(invalid(format!(
+                    "{name} has a CNAME record and other records"
+                )));
+            }
+        }
+
+        Ok(Self { origin, nodes })
+    }
+
+    /// The name at the top of the zone
+    pub fn origin(&self) -> &Name {
+        &self.origin
+    }
+
+    /// The answer to a question about `qname`, which is in the zone, of type `qtype`
+    ///
+    /// - A referral, if the name is at or below a zone cut, where the zone delegates a subdomain.
+    /// - The records of the type, if the name has them, or all of its records for ANY.
+    /// - The CNAME record, if the name is an alias.
+    /// - NODATA, if the name exists, but has no such records: NOERROR and no answer.
+    /// - NXDOMAIN, if the name doesn't exist.
+    ///
+    /// A name that doesn't exist, but matches a wildcard, is answered from the wildcard's records,
+    /// as if they were its own.
+    ///
+    /// Negative answers come with the SOA record in the authority section.
+    pub fn lookup(&self, qname: &Name, qtype: Qtype) -> Lookup {
+        if let Some(records) = self.zone_cut(qname) {
+            return self.referral(records);
+        }
+
+        let (records, synthesized) = match self.nodes.get(qname) {
+            Some(records) => (records, false),
+            None => match self.wildcard(qname) {
+                Some(records) => (records, true),
+                None => return self.negative(ResponseCode::NameError),
+            },
+        };
+
+        let mut answer: Vec<ResourceRecord> = match qtype {
+            Qtype::ANY => records.clone(),
+            qtype => {
+                let type_ = Type::from(u16::from(qtype));
+                let rrset = records.iter().filter(|rr| rr.type_ == type_);
+                let cname = records.iter().filter(|rr| rr.type_ == Type::CNAME);
+                match rrset.clone().next() {
+                    Some(_) => rrset.cloned().collect(),
+                    None => cname.cloned().collect(),
+                }
+            }
+        };
+        if answer.is_empty() {
+            return self.negative(ResponseCode::NoError);
+        }
+        if synthesized {
+            for rr in &mut answer {
+                rr.name = qname.clone();
+            }
+        }
+
+        Lookup {
+            rcode: ResponseCode::NoError,
+            extended_rcode: 0,
+            authoritative: true,
+            answer,
+            authority: vec![],
+            additional: vec![],
+        }
+    }
+
+    /// The records of the wildcard that `qname`, which doesn't exist, matches, if there is one
+    ///
+    /// The wildcard is `*` below the closest encloser, the nearest ancestor of `qname` that exists.
+    /// An empty non-terminal exists too, so it blocks the wildcards above it.
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc4592#section-3.3.1
+    fn wildcard(&self, qname: &Name) -> Option<&Vec<ResourceRecord>> {
+        let mut ancestor = qname.parent();
+        let closest_encloser = loop {
+            let name = ancestor?;
+            if self.nodes.contains_key(&name) {
+                break name;
+            }
+            ancestor = name.parent();
+        };
+        let wildcard = Name::from_str("*").ok()?.append(&closest_encloser).ok()?;
+        self.nodes.get(&wildcard)
+    }
+
+    /// The records at the zone cut that `qname` is at or below, if there is one
+    ///
+    /// A zone cut is a name below the origin that has NS records; the highest one above `qname` wins,
+    /// as the zone's data ends there, and what's below it, such as glue, isn't authoritative.
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc1034#section-4.2.1
+    fn zone_cut(&self, qname: &Name) -> Option<&[ResourceRecord]> {
+        let mut names = vec![];
+        let mut name = Some(qname.clone());
+        while let Some(below) = name.filter(|name| *name != self.origin) {
+            name = below.parent();
+            names.push(below);
+        }
+
+        names
+            .iter()
+            .rev()
+            .filter_map(|name| self.nodes.get(name))
+            .find(|records| records.iter().any(|rr| rr.type_ == Type::NS))
+            .map(Vec::as_slice)
+    }
+
+    /// A referral to the name servers of the delegation, with the `records` at its zone cut
+    ///
+    /// The addresses of the name servers that are in the zone, in-bailiwick, are added as glue,
+    /// as the servers couldn't be found otherwise if they're in the delegated subdomain.
+    fn referral(&self, records: &[ResourceRecord]) -> Lookup {
+        let ns: Vec<ResourceRecord> = records
+            .iter()
+            .filter(|rr| rr.type_ == Type::NS)
+            .cloned()
+            .collect();
+        let additional = ns
+            .iter()
+            .filter_map(|rr| match &rr.rdata {
+                RData::NS(target) if target.is_subdomain_of(&self.origin) => self.nodes.get(target),
+                _ => None,
+            })
+            .flatten()
+            .filter(|rr| matches!(rr.type_, Type::A | Type::AAAA))
+            .cloned()
+            .collect();
+
+        Lookup {
+            rcode: ResponseCode::NoError,
+            extended_rcode: 0,
+            authoritative: false,
+            answer: vec![],
+            authority: ns,
+            additional,
+        }
+    }
+
+    /// A negative answer with `rcode`
+    ///
+    /// The TTL of the SOA record is the lower of its own and its minimum field,
+    /// which is how long the answer may be cached.
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc2308#section-3
+    fn negative(&self, rcode: ResponseCode) -> Lookup {
+        let mut soa = self.nodes[&self.origin]
+            .iter()
+            .find(|rr| rr.type_ == Type::SOA)
+            .expect("A zone has a SOA record")
+            .clone();
+        if let RData::SOA { minimum, .. } = soa.rdata {
+            soa.ttl = soa.ttl.min(minimum);
+        }
+
+        Lookup {
+            rcode,
+            extended_rcode: 0,
+            authoritative: true,
+            answer: vec![],
+            authority: vec![soa],
+            additional: vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::ZoneError;
+    use crate::message::{Name, Qtype, RData, ResponseCode, Type};
+    use crate::zone::Zone;
+    use crate::zonefile;
+    use std::path::Path;
+
+    fn zone(contents: &str) -> Result<Zone, ZoneError> {
+        let origin: Name = "example.com".parse().unwrap();
+        let records =
+            zonefile::parse(contents, Path::new("example.com.zone"), Some(&origin)).unwrap();
+        Zone::new(origin, records)
+    }
+
+    const ZONE: &str = "$TTL 3600
+@           SOA   ns hostmaster 1 7200 900 604800 300
+            NS    ns
+ns          A     192.0.2.1
+www         CNAME ns
+a.b.c       TXT   \"deep\"
+mail        A     192.0.2.2
+            A     192.0.2.3
+            A     192.0.2.2
+            AAAA  2001:db8::2
+";
+
+    fn name(name: &str) -> Name {
+        name.parse().unwrap()
+    }
+
+    #[test]
+    fn lookup() {
+        let zone = zone(ZONE).unwrap();
+
+        // The records of the type, kept once each, whatever the case of the name
+        let lookup = zone.lookup(&name("MAIL.example.com"), Qtype::A);
+        assert_eq!(ResponseCode::NoError, lookup.rcode);
+        assert_eq!(2, lookup.answer.len());
+        assert!(lookup.authority.is_empty());
+        assert_eq!(
+            3,
+            zone.lookup(&name("mail.example.com"), Qtype::ANY)
+                .answer
+                .len()
+        );
+
+        // An alias
+        let lookup = zone.lookup(&name("www.example.com"), Qtype::A);
+        assert_eq!(Type::CNAME, lookup.answer[0].type_);
+
+        // NODATA, with the SOA record's TTL capped by its minimum
+        let lookup = zone.lookup(&name("ns.example.com"), Qtype::MX);
+        assert_eq!(ResponseCode::NoError, lookup.rcode);
+        assert!(lookup.answer.is_empty());
+        assert_eq!(Type::SOA, lookup.authority[0].type_);
+        assert_eq!(300, lookup.authority[0].ttl);
+
+        // Empty non-terminals exist.
+        for ent in ["c.example.com", "b.c.example.com"] {
+            let lookup = zone.lookup(&name(ent), Qtype::A);
+            assert_eq!(ResponseCode::NoError, lookup.rcode, "{ent}");
+            assert!(lookup.answer.is_empty());
+        }
+
+        // NXDOMAIN
+        let lookup = zone.lookup(&name("d.c.example.com"), Qtype::A);
+        assert_eq!(ResponseCode::NameError, lookup.rcode);
+        assert!(lookup.answer.is_empty());
+        assert_eq!(Type::SOA, lookup.authority[0].type_);
+    }
+
+    #[test]
+    fn referrals() {
+        let zone = zone(
+            "$TTL 3600
+@               SOA   ns hostmaster 1 7200 900 604800 300
+                NS    ns
+ns              A     192.0.2.1
+sub             NS    ns1.sub
+                NS    ns
+                NS    ns.example.net.
+ns1.sub         A     192.0.2.53
+                AAAA  2001:db8::53
+                TXT   \"not glue\"
+deeper.x.sub    NS    ns1.sub
+",
+        )
+        .unwrap();
+
+        for (qname, qtype) in [
+            ("sub.example.com", Qtype::NS),
+            ("sub.example.com", Qtype::A),
+            ("www.sub.example.com", Qtype::A),
+            // Glue isn't authoritative data, and the highest zone cut wins.
+            ("ns1.sub.example.com", Qtype::A),
+            ("a.deeper.x.sub.example.com", Qtype::A),
+        ] {
+            let lookup = zone.lookup(&name(qname), qtype);
+            assert_eq!(ResponseCode::NoError, lookup.rcode, "{qname}");
+            assert!(!lookup.authoritative);
+            assert!(lookup.answer.is_empty());
+            assert_eq!(3, lookup.authority.len());
+            assert!(lookup
+                .authority
+                .iter()
+                .all(|rr| rr.type_ == Type::NS && rr.name == name("sub.example.com")));
+            // The addresses of the name servers in the zone, but not out of it
+            assert_eq!(
+                vec![Type::A, Type::AAAA, Type::A],
+                lookup
+                    .additional
+                    .iter()
+                    .map(|rr| rr.type_)
+                    .collect::<Vec<_>>()
+            );
+        }
+
+        // The NS records at the origin aren't a zone cut.
+        let lookup = zone.lookup(&name("example.com"), Qtype::NS);
+        assert!(lookup.authoritative);
+        assert_eq!(1, lookup.answer.len());
+    }
+
+    #[test]
+    fn wildcards() {
+        let zone = zone(
+            "$TTL 3600
+@               SOA   ns hostmaster 1 7200 900 604800 300
+www             A     192.0.2.1
+*.apps          A     192.0.2.80
+                TXT   \"wild\"
+x.y.apps        A     192.0.2.81
+*.alias         CNAME www
+",
+        )
+        .unwrap();
+
+        // Synthesized, with the name of the question as the owner, however many labels it matches
+        for qname in ["foo.apps.example.com", "a.b.apps.example.com"] {
+            let lookup = zone.lookup(&name(qname), Qtype::A);
+            assert!(lookup.authoritative);
+            assert_eq!(1, lookup.answer.len());
+            assert_eq!(name(qname), lookup.answer[0].name);
+            assert_eq!(RData::A([192, 0, 2, 80].into()), lookup.answer[0].rdata);
+        }
+        let lookup = zone.lookup(&name("foo.alias.example.com"), Qtype::A);
+        assert_eq!(name("foo.alias.example.com"), lookup.answer[0].name);
+        assert_eq!(Type::CNAME, lookup.answer[0].type_);
+
+        // The wildcard exists, but not with the type: NODATA
+        let lookup = zone.lookup(&name("foo.apps.example.com"), Qtype::MX);
+        assert_eq!(ResponseCode::NoError, lookup.rcode);
+        assert!(lookup.answer.is_empty());
+        assert_eq!(Type::SOA, lookup.authority[0].type_);
+
+        // Names that exist, even as empty non-terminals, aren't synthesized.
+        for qname in ["apps.example.com", "y.apps.example.com"] {
+            let lookup = zone.lookup(&name(qname), Qtype::A);
+            assert_eq!(ResponseCode::NoError, lookup.rcode, "{qname}");
+            assert!(lookup.answer.is_empty(), "{qname}");
+        }
+        let lookup = zone.lookup(&name("*.apps.example.com"), Qtype::TXT);
+        assert_eq!(name("*.apps.example.com"), lookup.answer[0].name);
+
+        // The empty non-terminal "y.apps" is the closest encloser, and there's no "*.y.apps".
+        // Neither is there a wildcard at the origin.
+        for qname in ["z.y.apps.example.com", "nx.example.com"] {
+            let lookup = zone.lookup(&name(qname), Qtype::A);
+            assert_eq!(ResponseCode::NameError, lookup.rcode, "{qname}");
+        }
+    }
+
+    #[test]
+    fn invalid_zones() {
+        for (contents, expected) in [
+            ("www A 192.0.2.1", "there is no SOA record"),
+            (
+                "@ SOA ns hostmaster 1 2 3 4 5\nwww.example.org. A 192.0.2.1",
+                "www.example.org. is outside of the zone",
+            ),
+            (
+                "www SOA ns hostmaster 1 2 3 4 5",
+                "the SOA record is at www.example.com.",
+            ),
+            (
+                "@ SOA ns hostmaster 1 2 3 4 5\n@ SOA ns hostmaster 2 2 3 4 5",
+                "there is more than one SOA record",
+            ),
+            (
+                "@ SOA ns hostmaster 1 2 3 4 5\nwww CNAME @\nwww A 192.0.2.1",
+                "www.example.com. has a CNAME record and other records",
+            ),
+        ] {
+            match zone(&format!("$TTL 60\n{contents}\n")) {
+                Err(ZoneError::Invalid(origin, message)) => {
+                    assert_eq!("example.com.", origin);
+                    assert_eq!(expected, message);
+                }
+                res => panic!("{contents}: {res:?}"),
+            }
+        }
+    }
+}
@@ -0,0 +1,774 @@
+//! # Zone Files
+//!
+//! Reading the records of a zone from a master file
+//!
+//! https://www.rfc-editor.org/rfc/rfc1035#section-5
+
+use crate::constants::MAX_ZONE_INCLUDE_DEPTH;
+use crate::errors::ZoneError;
+use crate::message::{Class, Name, RData, ResourceRecord, Type};
+use deku::no_std_io::Cursor;
+use deku::prelude::*;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Read the records of the master file at `path`, in the order in which they appear
+///
+/// Relative names are completed with `origin` until a `$ORIGIN` directive changes it.
+/// Included files are read in place of their `$INCLUDE` directives, with their paths relative to
+/// the directory of the file that includes them.
+pub fn read(path: &Path, origin: Option<&Name>) -> Result<Vec<ResourceRecord>, ZoneError> {
+    let mut records = vec![];
+    State::new(origin.cloned()).read(path, 0, &mut records)?;
+    Ok(records)
+}
+
+/// Parse the records of a master file with the `contents`, as [`read`] does
+///
+/// `path` is where the file is, which errors refer to, and which included files are relative to.
+pub fn parse(
+    contents: &str,
+    path: &Path,
+    origin: Option<&Name>,
+) -> Result<Vec<ResourceRecord>, ZoneError> {
+    let mut records = vec![];
+    State::new(origin.cloned()).parse(contents, path, 0, &mut records)?;
+    Ok(records)
+}
+
+/// What an entry of a master file inherits from the entries before it
+#[derive(Clone, Debug)]
+struct State {
+    /// The origin that relative names are completed with
+    origin: Option<Name>,
+
+    /// The TTL of records that don't give one, from `$TTL`
+    default_ttl: Option<u32>,
+
+    /// The last TTL that a record gave, for records that don't give one when there's no `$TTL`
+    last_ttl: Option<u32>,
+
+    /// The owner of the previous record, for records that leave it blank
+    last_owner: Option<Name>,
+}
+
+/// A token of an entry
+#[derive(Debug)]
+struct Token {
+    /// The text, with its escapes as they are
+    text: String,
+
+    /// Was the token in quotes
+    quoted: bool,
+}
+
+/// An entry: a line, or several lines joined by parentheses, without comments
+#[derive(Debug)]
+struct Entry {
+    /// The line that the entry starts on, counting from 1
+    line: usize,
+
+    /// Does the entry start with a blank, so that its owner is that of the previous record
+    blank_owner: bool,
+
+    /// The tokens; there is at least one
+    tokens: Vec<Token>,
+}
+
+impl State {
+    fn new(origin: Option<Name>) -> Self {
+        Self {
+            origin,
+            default_ttl: None,
+            last_ttl: None,
+            last_owner: None,
+        }
+    }
+
+    /// Read the master file at `path`, which is included `depth` levels deep, into `records`
+    fn read(
+        &mut self,
+        path: &Path,
+        depth: usize,
+        records: &mut Vec<ResourceRecord>,
+    ) -> Result<(), ZoneError> {
+        let contents =
+            fs::read_to_string(path).map_err(|e| ZoneError::Read(path.display().to_string(), e))?;
+        self.parse(&contents, path, depth, records)
+    }
+
+    /// Parse the `contents` of the master file at `path` into `records`
+    fn parse(
+        &mut self,
+        contents: &str,
+        path: &Path,
+        depth: usize,
+        records: &mut Vec<ResourceRecord>,
+    ) -> Result<(), ZoneError> {
+        let syntax = |line, message| ZoneError::Syntax(path.display().to_string(), line, message);
+
+        for entry in entries(contents).map_err(|(line, message)| syntax(line, message))? {
+            let directive = match &entry.tokens[0] {
+                token if entry.blank_owner || token.quoted => None,
+                token => token
+                    .text
+                    .starts_with('$')
+                    .then(|| token.text.to_ascii_uppercase()),
+            };
+            let args = &entry.tokens[1..];
+            match directive.as_deref() {
+                Some("$ORIGIN") => {
+                    let [origin] = args else {
+                        return Err(syntax(entry.line, "expected $ORIGIN <name>".to_string()));
+                    };
+                    let origin = self.name(origin).map_err(|e| syntax(entry.line, e))?;
+                    self.origin = Some(origin);
+                }
+                Some("$TTL") => {
+                    let [ttl] = args else {
+                        return Err(syntax(entry.line, "expected $TTL <ttl>".to_string()));
+                    };
+                    let ttl = record_ttl(ttl).map_err(|e| syntax(entry.line, e))?;
+                    self.default_ttl = Some(ttl);
+                }
+                Some("$INCLUDE") => {
+                    let (file, origin) = match args {
+                        [file] => (file, None),
+                        [file, origin] => (file, Some(origin)),
+                        _ => {
+                            let message = "expected $INCLUDE <file> [<origin>]".to_string();
+                            return Err(syntax(entry.line, message));
+                        }
+                    };
+                    if depth >= MAX_ZONE_INCLUDE_DEPTH {
+                        let message = format!(
+                            "$INCLUDE is nested more than {MAX_ZONE_INCLUDE_DEPTH} levels deep"
+                        );
+                        return Err(syntax(entry.line, message));
+                    }
+                    // The included file starts from where we are, and doesn't change where we are.
+                    let mut state = self.clone();
+                    if let Some(origin) = origin {
+                        state.origin = Some(self.name(origin).map_err(|e| syntax(entry.line, e))?);
+                    }
+                    let dir = path.parent().unwrap_or(Path::new(""));
+                    state.read(&dir.join(&file.text), depth + 1, records)?;
+                }
+                Some(directive) => {
+                    let message = format!("unsupported directive {directive}");
+                    return Err(syntax(entry.line, message));
+                }
+                None => {
+                    let record = self.record(&entry).map_err(|e| syntax(entry.line, e))?;
+                    records.push(record);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The record of `entry`, which is of the form `[<owner>] [<ttl>] [<class>] <type> <rdata>`,
+    /// where the TTL and the class can come in either order
+    fn record(&mut self, entry: &Entry) -> Result<ResourceRecord, String> {
+        let mut tokens = entry.tokens.as_slice();
+        let owner = if entry.blank_owner {
+            self.last_owner
+                .clone()
+                .ok_or("the owner is blank, and there is no previous one")?
+        } else {
+            let owner = self.name(&tokens[0])?;
+            tokens = &tokens[1..];
+            owner
+        };
+
+        let mut ttl = None;
+        let mut class = None;
+        while let Some((token, rest)) = tokens.split_first() {
+            if ttl.is_none() && time(&token.text).is_some() {
+                ttl = Some(record_ttl(token)?);
+            } else if class.is_none() && parse_class(&token.text).is_some() {
+                class = parse_class(&token.text);
+            } else {
+                break;
+            }
+            tokens = rest;
+        }
+        if class.is_some_and(|class| class != Class::IN) {
+            return Err("only the IN class is supported".to_string());
+        }
+
+        let (type_, rdata) = tokens.split_first().ok_or("the type is missing")?;
+        let type_ = parse_type(&type_.text).ok_or(format!("unknown type {}", type_.text))?;
+        let rdata = self.rdata(type_, rdata)?;
+
+        if ttl.is_some() {
+            self.last_ttl = ttl;
+        }
+        // RFC 2308, section 4: the SOA's minimum used to be the default TTL.
+        let soa_minimum = match rdata {
+            RData::SOA { minimum, .. } => Some(minimum),
+            _ => None,
+        };
+        let ttl = ttl
+            .or(self.default_ttl)
+            .or(self.last_ttl)
+            .or(soa_minimum)
+            .ok_or("there is no TTL, and no $TTL before it")?;
+        self.last_owner = Some(owner.clone());
+
+        Ok(ResourceRecord::new(owner, type_, Class::IN, ttl, rdata))
+    }
+
+    /// The RDATA of type `type_` in `tokens`, in its presentation format, or in the generic one
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc3597#section-5
+    fn rdata(&self, type_: Type, tokens: &[Token]) -> Result<RData, String> {
+        if tokens.first().is_some_and(|t| !t.quoted && t.text == "\\#") {
+            return generic_rdata(type_, &tokens[1..]);
+        }
+
+        let expect = |n: usize| match tokens.len() == n {
+            true => Ok(()),
+            false => Err(format!(
+                "{type_} data has {n} fields, but there are {}",
+                tokens.len()
+            )),
+        };
+        let rdata = match type_ {
+            Type::A => {
+                expect(1)?;
+                RData::A(number(&tokens[0], "IPv4 address")?)
+            }
+            Type::AAAA => {
+                expect(1)?;
+                RData::AAAA(number(&tokens[0], "IPv6 address")?)
+            }
+            Type::NS => {
+                expect(1)?;
+                RData::NS(self.name(&tokens[0])?)
+            }
+            Type::CNAME => {
+                expect(1)?;
+                RData::CNAME(self.name(&tokens[0])?)
+            }
+            Type::PTR => {
+                expect(1)?;
+                RData::PTR(self.name(&tokens[0])?)
+            }
+            Type::SOA => {
+                expect(7)?;
+                RData::SOA {
+                    mname: self.name(&tokens[0])?,
+                    rname: self.name(&tokens[1])?,
+                    serial: number(&tokens[2], "serial")?,
+                    refresh: time(&tokens[3].text).ok_or("invalid refresh")?,
+                    retry: time(&tokens[4].text).ok_or("invalid retry")?,
+                    expire: time(&tokens[5].text).ok_or("invalid expire")?,
+                    minimum: time(&tokens[6].text).ok_or("invalid minimum")?,
+                }
+            }
+            Type::MX => {
+                expect(2)?;
+                RData::MX {
+                    preference: number(&tokens[0], "preference")?,
+                    exchange: self.name(&tokens[1])?,
+                }
+            }
+            Type::TXT => {
+                if tokens.is_empty() {
+                    return Err("TXT data needs at least one string".to_string());
+                }
+                RData::TXT(
+                    tokens
+                        .iter()
+                        .map(character_string)
+                        .collect::<Result<_, _>>()?,
+                )
+            }
+            Type::SRV => {
+                expect(4)?;
+                RData::SRV {
+                    priority: number(&tokens[0], "priority")?,
+                    weight: number(&tokens[1], "weight")?,
+                    port: number(&tokens[2], "port")?,
+                    target: self.name(&tokens[3])?,
+                }
+            }
+            Type::CAA => {
+                expect(3)?;
+                let tag = tokens[1].text.as_bytes();
+                if tag.is_empty() || !tag.iter().all(u8::is_ascii_alphanumeric) {
+                    return Err(format!("invalid CAA tag \"{}\"", tokens[1].text));
+                }
+                RData::CAA {
+                    flags: number(&tokens[0], "flags")?,
+                    tag: tag.to_vec(),
+                    value: character_string(&tokens[2])?,
+                }
+            }
+            Type::OPT => return Err("OPT records can't be in a zone".to_string()),
+            Type::Unknown(_) => {
+                return Err(format!(
+                "{type_} data can only be given in the generic format, as \\# <length> <hex data>"
+            ))
+            }
+        };
+
+        Ok(rdata)
+    }
+
+    /// The name in `token`, which is either absolute, with a trailing dot, or relative to the origin
+    ///
+    /// `@` stands for the origin itself.
+    fn name(&self, token: &Token) -> Result<Name, String> {
+        let origin = || {
+            self.origin.as_ref().ok_or(format!(
+                "\"{}\" is relative, but there is no origin",
+                token.text
+            ))
+        };
+        if token.text == "@" && !token.quoted {
+            return origin().cloned();
+        }
+
+        let name: Name = token.text.parse().map_err(|e| format!("{e}"))?;
+        // A trailing dot is absolute unless it's escaped, by an odd number of backslashes.
+        let absolute = token
+            .text
+            .strip_suffix('.')
+            .is_some_and(|rest| rest.bytes().rev().take_while(|&b| b == b'\\').count() % 2 == 0);
+        if absolute {
+            Ok(name)
+        } else {
+            name.append(origin()?).map_err(|e| format!("{e}"))
+        }
+    }
+}
+
+/// Split `contents` into entries
+///
+/// Comments run from `;` to the end of the line, parentheses join lines, and quotes keep blanks,
+/// semicolons and parentheses in a token. Errors come with the line they're on.
+fn entries(contents: &str) -> Result<Vec<Entry>, (usize, String)> {
+    let mut entries = vec![];
+    let mut line = 1;
+    let mut parentheses = 0;
+    let mut entry = Entry::new(line);
+    let mut line_start = true;
+    let mut chars = contents.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if line_start {
+            entry.blank_owner = c == ' ' || c == '\t';
+            line_start = false;
+        }
+        match c {
+            '\n' => {
+                line += 1;
+                if parentheses == 0 {
+                    if !entry.tokens.is_empty() {
+                        entries.push(entry);
+                    }
+                    entry = Entry::new(line);
+                    line_start = true;
+                }
+            }
+            ' ' | '\t' | '\r' => {}
+            ';' => while chars.next_if(|&c| c != '\n').is_some() {},
+            '(' => parentheses += 1,
+            ')' if parentheses == 0 => return Err((line, "unbalanced \")\"".to_string())),
+            ')' => parentheses -= 1,
+            '"' => {
+                let start = line;
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => {
+                            text.push('\\');
+                            text.extend(chars.next());
+                        }
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            text.push(c);
+                        }
+                        None => return Err((start, "unterminated quotes".to_string())),
+                    }
+                }
+                entry.tokens.push(Token { text, quoted: true });
+            }
+            c => {
+                let mut text = String::from(c);
+                if c == '\\' {
+                    text.extend(chars.next());
+                }
+                while let Some(c) = chars
+                    .next_if(|c| !matches!(c, ' ' | '\t' | '\r' | '\n' | ';' | '(' | ')' | '"'))
+                {
+                    text.push(c);
+                    if c == '\\' {
+                        text.extend(chars.next());
+                    }
+                }
+                entry.tokens.push(Token {
+                    text,
+                    quoted: false,
+                });
+            }
+        }
+    }
+    if parentheses > 0 {
+        return Err((entry.line, "unbalanced \"(\"".to_string()));
+    }
+    if !entry.tokens.is_empty() {
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+impl Entry {
+    fn new(line: usize) -> Self {
+        Self {
+            line,
+            blank_owner: false,
+            tokens: vec![],
+        }
+    }
+}
+
+/// A time value, such as a TTL: a number of seconds, or numbers with units, as in `1h30m`
+///
+/// The units are `s`, `m`, `h`, `d` and `w`, in either case.
+fn time(s: &str) -> Option<u32> {
+    if s.bytes().all(|b| b.is_ascii_digit()) {
+        return s.parse().ok();
+    }
+    let mut total: u32 = 0;
+    let mut value: Option<u32> = None;
+    for c in s.chars() {
+        if let Some(digit) = c.to_digit(10) {
+            value = Some(value.unwrap_or(0).checked_mul(10)?.checked_add(digit)?);
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return None,
+        };
+        total = total.checked_add(value.take()?.checked_mul(unit)?)?;
+    }
+    match value {
+        None => Some(total),
+        Some(_) => None,
+    }
+}
+
+/// The TTL of a record in `token`, which is at most 31 bits long, as RFC 2181, section 8, says
+fn record_ttl(token: &Token) -> Result<u32, String> {
+    match time(&token.text) {
+        Some(ttl) if ttl <= i32::MAX as u32 => Ok(ttl),
+        _ => Err(format!("invalid TTL \"{}\"", token.text)),
+    }
+}
+
+/// The class with the mnemonic `s`, or `CLASSNNN`
+fn parse_class(s: &str) -> Option<Class> {
+    match s.to_ascii_uppercase().as_str() {
+        "IN" => Some(Class::IN),
+        "CS" => Some(Class::Unknown(2)),
+        "CH" => Some(Class::Unknown(3)),
+        "HS" => Some(Class::Unknown(4)),
+        s => s
+            .strip_prefix("CLASS")?
+            .parse::<u16>()
+            .ok()
+            .map(Class::from),
+    }
+}
+
+/// The type with the mnemonic `s`, or `TYPENNN`
+fn parse_type(s: &str) -> Option<Type> {
+    let type_ = match s.to_ascii_uppercase().as_str() {
+        "A" => Type::A,
+        "NS" => Type::NS,
+        "CNAME" => Type::CNAME,
+        "SOA" => Type::SOA,
+        "PTR" => Type::PTR,
+        "MX" => Type::MX,
+        "TXT" => Type::TXT,
+        "AAAA" => Type::AAAA,
+        "SRV" => Type::SRV,
+        "OPT" => Type::OPT,
+        "CAA" => Type::CAA,
+        s => Type::from(s.strip_prefix("TYPE")?.parse::<u16>().ok()?),
+    };
+    Some(type_)
+}
+
+/// The number, or another value that is parsed as it is, such as an address, in `token`
+fn number<T: FromStr>(token: &Token, what: &str) -> Result<T, String> {
+    token
+        .text
+        .parse()
+        .map_err(|_| format!("invalid {what} \"{}\"", token.text))
+}
+
+/// The character string in `token`, with its escapes decoded
+///
+/// A byte is escaped as `\DDD`, and any other character, such as a quote, as `\X`.
+fn character_string(token: &Token) -> Result<Vec<u8>, String> {
+    let invalid = || format!("invalid escape sequence in \"{}\"", token.text);
+    let mut string = vec![];
+    let mut bytes = token.text.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            string.push(b);
+            continue;
+        }
+        match bytes.next() {
+            Some(d) if d.is_ascii_digit() => {
+                let digits = [
+                    d,
+                    bytes.next().ok_or_else(invalid)?,
+                    bytes.next().ok_or_else(invalid)?,
+                ];
+                let value = std::str::from_utf8(&digits)
+                    .ok()
+                    .filter(|digits| digits.bytes().all(|d| d.is_ascii_digit()))
+                    .and_then(|digits| digits.parse::<u8>().ok())
+                    .ok_or_else(invalid)?;
+                string.push(value);
+            }
+            Some(c) => string.push(c),
+            None => return Err(invalid()),
+        }
+    }
+    if string.len() > u8::MAX as usize {
+        return Err(format!(
+            "a character string is {} bytes long, but at most 255 are allowed",
+            string.len()
+        ));
+    }
+    Ok(string)
+}
+
+/// RDATA of type `type_` in the generic format, in `tokens`: the length, and the data in hex
+///
+/// Data of the known types is read as it would be in a message.
+fn generic_rdata(type_: Type, tokens: &[Token]) -> Result<RData, String> {
+    if type_ == Type::OPT {
+        return Err("OPT records can't be in a zone".to_string());
+    }
+    let (len, hex) = tokens
+        .split_first()
+        .ok_or("the length of the data is missing")?;
+    let len: u16 = number(len, "length")?;
+    let hex: String = hex.iter().map(|token| token.text.as_str()).collect();
+    if hex.len() % 2 != 0 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(format!("invalid hex data \"{hex}\""));
+    }
+    let data: Vec<u8> = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).expect("Hex digits"))
+        .collect();
+    if data.len() != len as usize {
+        return Err(format!(
+            "the data is {} bytes long instead of {len}",
+            data.len()
+        ));
+    }
+
+    if let Type::Unknown(_) = type_ {
+        return Ok(RData::Unknown(data));
+    }
+    let mut cursor = Cursor::new(&data);
+    let mut reader = Reader::new(&mut cursor);
+    RData::from_reader_with_ctx(&mut reader, (type_, Class::IN, len))
+        .map_err(|e| format!("invalid {type_} data: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::ZoneError;
+    use crate::fixtures::TempDir;
+    use crate::message::{RData, ResourceRecord};
+    use crate::zonefile::{parse, read};
+    use std::path::Path;
+
+    fn presentation(records: &[ResourceRecord]) -> Vec<String> {
+        records
+            .iter()
+            .map(|rr| {
+                format!(
+                    "{} {} {} {} {}",
+                    rr.name, rr.ttl, rr.class, rr.type_, rr.rdata
+                )
+            })
+            .collect()
+    }
+
+    fn syntax_error(contents: &str) -> (usize, String) {
+        match parse(contents, Path::new("test.zone"), None) {
+            Err(ZoneError::Syntax(file, line, message)) => {
+                assert_eq!("test.zone", file);
+                (line, message)
+            }
+            res => panic!("{res:?}"),
+        }
+    }
+
+    #[test]
+    fn master_file_syntax() {
+        let contents = r#"
+$ORIGIN example.com.
+$TTL 1h
+@       IN  SOA  ns1 hostmaster (
+                 2024010101 ; serial
+                 2h 15m 1w  ; refresh, retry, expire
+                 5m )       ; minimum
+        IN  NS   ns1
+            NS   ns2.example.net.
+ns1     300 A    192.0.2.1
+            AAAA 2001:db8::1
+www     IN 60 CNAME @
+@           MX   10 mail
+txt         TXT  "hello world" plain "semi;colon (\"quoted\")" \065
+_sip._udp   SRV  1 2 5060 sip
+@           CAA  0 issue "letsencrypt.org"
+ptr         PTR  www
+$ORIGIN sub
+a           A    192.0.2.2
+gen         TYPE65534 \# 3 0a0b0c
+gen-a       A    \# 4 c0000203
+"#;
+        let records = parse(contents, Path::new("example.com.zone"), None).unwrap();
+        assert_eq!(
+            vec![
+                "example.com. 3600 IN SOA ns1.example.com. hostmaster.example.com. 2024010101 7200 900 604800 300",
+                "example.com. 3600 IN NS ns1.example.com.",
+                "example.com. 3600 IN NS ns2.example.net.",
+                "ns1.example.com. 300 IN A 192.0.2.1",
+                "ns1.example.com. 3600 IN AAAA 2001:db8::1",
+                "www.example.com. 60 IN CNAME example.com.",
+                "example.com. 3600 IN MX 10 mail.example.com.",
+                "txt.example.com. 3600 IN TXT \"hello world\" \"plain\" \"semi;colon (\\\"quoted\\\")\" \"A\"",
+                "_sip._udp.example.com. 3600 IN SRV 1 2 5060 sip.example.com.",
+                "example.com. 3600 IN CAA 0 issue \"letsencrypt.org\"",
+                "ptr.example.com. 3600 IN PTR www.example.com.",
+                "a.sub.example.com. 3600 IN A 192.0.2.2",
+                "gen.sub.example.com. 3600 IN TYPE65534 \\# 3 0a0b0c",
+                "gen-a.sub.example.com. 3600 IN A 192.0.2.3",
+            ],
+            presentation(&records)
+        );
+        assert_eq!(RData::A([192, 0, 2, 3].into()), records[13].rdata);
+    }
+
+    #[test]
+    fn ttls_are_inherited() {
+        // Without $TTL, a record takes the last TTL that was given, and the SOA record its minimum.
+        let contents = "@ SOA ns hostmaster 1 2 3 4 5\na 100 A 192.0.2.1\nb A 192.0.2.2\n";
+        let origin = "example.com".parse().unwrap();
+        let records = parse(contents, Path::new("test.zone"), Some(&origin)).unwrap();
+        assert_eq!(
+            vec![5, 100, 100],
+            records.iter().map(|rr| rr.ttl).collect::<Vec<_>>()
+        );
+
+        let (line, message) = syntax_error("$ORIGIN example.com.\na A 192.0.2.1\n");
+        assert_eq!(
+            (2, "there is no TTL, and no $TTL before it"),
+            (line, message.as_str())
+        );
+    }
+
+    #[test]
+    fn included_files() {
+        let dir = TempDir::new(
+            "zonefile",
+            &[
+                (
+                    "example.com.zone",
+                    "$ORIGIN example.com.\n$TTL 60\n\
+                     @ SOA ns hostmaster 1 2 3 4 5\n\
+                     $INCLUDE hosts/lab.zone lab\n\
+                     www A 192.0.2.1\n",
+                ),
+                // The included file's $ORIGIN and $TTL don't leak out of it.
+                (
+                    "hosts/lab.zone",
+                    "pc1 A 10.0.0.1\n$ORIGIN other.example.com.\n$TTL 30\npc2 A 10.0.0.2\n",
+                ),
+                ("loop.zone", "$INCLUDE loop.zone\n"),
+            ],
+        );
+
+        let records = read(&dir.join("example.com.zone"), None).unwrap();
+        let loop_error = read(&dir.join("loop.zone"), None).unwrap_err();
+        let missing_error = read(&dir.join("missing.zone"), None).unwrap_err();
+
+        assert_eq!(
+            vec![
+                "example.com. 60 IN SOA ns.example.com. hostmaster.example.com. 1 2 3 4 5",
+                "pc1.lab.example.com. 60 IN A 10.0.0.1",
+                "pc2.other.example.com. 30 IN A 10.0.0.2",
+                "www.example.com. 60 IN A 192.0.2.1",
+            ],
+            presentation(&records)
+        );
+        assert!(
+            matches!(&loop_error, ZoneError::Syntax(file, 1, message)
+                if file.ends_with("loop.zone") && message.contains("nested")),
+            "{loop_error}"
+        );
+        assert!(matches!(missing_error, ZoneError::Read(..)));
+    }
+
+    #[test]
+    fn syntax_errors_point_to_the_line() {
+        let soa = "$ORIGIN example.com.\n$TTL 60\n@ SOA ns hostmaster (\n1 2 3 4 5 )\n";
+        for (entry, expected) in [
+            ("www A 192.0.2", "invalid IPv4 address \"192.0.2\""),
+            (
+                "www A 192.0.2.1 192.0.2.2",
+                "A data has 1 fields, but there are 2",
+            ),
+            ("www HINFO PC Linux", "unknown type HINFO"),
+            ("www CH A 192.0.2.1", "only the IN class is supported"),
+            (
+                "www TYPE99 1",
+                "TYPE99 data can only be given in the generic format",
+            ),
+            (
+                "www A \\# 4 c00002",
+                "the data is 3 bytes long instead of 4",
+            ),
+            ("www A \\# 3 c00002", "invalid A data"),
+            ("www MX \\# 2 000a", "invalid MX data"),
+            ("www TXT \"unterminated", "unterminated quotes"),
+            ("www NS ( ns1", "unbalanced \"(\""),
+            ("www NS ns1 )", "unbalanced \")\""),
+            ("www..a A 192.0.2.1", "Empty label"),
+            (
+                "$GENERATE 1-2 a$ A 192.0.2.$",
+                "unsupported directive $GENERATE",
+            ),
+        ] {
+            let (line, message) = syntax_error(&format!("{soa}{entry}\n"));
+            assert!(message.contains(expected), "{entry}: {message}");
+            assert_eq!(5, line, "{entry}");
+        }
+
+        let (line, message) = syntax_error("\n\nwww A 192.0.2.1\n");
+        assert_eq!(3, line);
+        assert_eq!("\"www\" is relative, but there is no origin", message);
+    }
+}